@@ -0,0 +1,139 @@
+//! Timings for representative workloads, using only `std::time`.
+//!
+//! Run with `cargo bench`. Each workload is run several times and the best
+//! and median times are reported, both as assembled and after
+//! `optimize::fuse_instructions`, and then with the register engine.
+
+use std::time::{Duration, Instant};
+
+use pettyscript_bytecode::{
+    assembler::compile_str,
+    op_codes::OpCode,
+    optimize::fuse_instructions,
+    program::Program,
+    register,
+    value::Value,
+    vm::{self, VmError},
+};
+
+type Engine = fn(&Program) -> Result<Vec<Value>, VmError>;
+
+const RUNS: usize = 10;
+
+fn main() {
+    let workloads = [
+        ("arithmetic loop", arithmetic_loop(200_000)),
+        ("string concatenation", string_concatenation(100_000)),
+        ("string constants", string_constants(100_000)),
+        ("recursive calls", recursive_calls(24)),
+        ("global variables", global_variables(100_000)),
+    ];
+    for (name, program) in &workloads {
+        let fused = fuse_instructions(program);
+        report(name, program, vm::create_and_run);
+        report(&format!("{name} (fused)"), &fused, vm::create_and_run);
+        report(
+            &format!("{name} (register)"),
+            program,
+            register::create_and_run,
+        );
+        report(
+            &format!("{name} (fused, register)"),
+            &fused,
+            register::create_and_run,
+        );
+    }
+}
+
+fn report(name: &str, program: &Program, engine: Engine) {
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            engine(program).unwrap();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    println!(
+        "{name:<40} best {:>9.3} ms   median {:>9.3} ms",
+        times[0].as_secs_f64() * 1000.0,
+        times[RUNS / 2].as_secs_f64() * 1000.0,
+    );
+}
+
+/// `examples/while_loop.pty`, summing instead of multiplying so the result
+/// stays small.
+fn arithmetic_loop(iterations: u32) -> Program {
+    compile_str(&format!(
+        "0 0 @start dup {iterations} < ?end 1 + dup_swap + swap $start @end"
+    ))
+}
+
+fn string_concatenation(iterations: u32) -> Program {
+    compile_str(&format!(
+        "0 \"\" @start swap dup {iterations} < ?end 1 + swap \"ab\" + $start @end"
+    ))
+}
+
+/// Loads, duplicates and compares a 1 KiB string constant.
+fn string_constants(iterations: u32) -> Program {
+    let long = "x".repeat(1024);
+    compile_str(&format!(
+        "0 @start dup {iterations} < ?end \"{long}\" dup = pop 1 + $start @end"
+    ))
+}
+
+/// Naive recursive Fibonacci.
+fn recursive_calls(n: i64) -> Program {
+    let mut program = Program::new();
+    let entry = program.len() + 5;
+    let fib = program.push_func(|func| {
+        func.push_opcode(OpCode::Dup);
+        func.push_literal(2);
+        func.push_opcode(OpCode::Lt);
+        func.push_if_or_else(
+            |_| {},
+            |orelse| {
+                orelse.push_opcode(OpCode::Dup);
+                orelse.push_literal(1);
+                orelse.push_opcode(OpCode::Sub);
+                orelse.call_func(entry);
+                orelse.push_opcode(OpCode::Swap);
+                orelse.push_literal(2);
+                orelse.push_opcode(OpCode::Sub);
+                orelse.call_func(entry);
+                orelse.push_opcode(OpCode::Add);
+            },
+        );
+    });
+    program.push_literal(n);
+    program.call_func(fib);
+    program
+}
+
+/// A loop that keeps its counter and accumulator in globals.
+fn global_variables(iterations: i64) -> Program {
+    let mut program = Program::new();
+    program.push_literal(0);
+    program.store_name("sum");
+    program.push_literal(0);
+    program.store_name("i");
+    program.push_while_loop(
+        |cond| {
+            cond.load_name("i");
+            cond.push_literal(iterations);
+            cond.push_opcode(OpCode::Lt);
+        },
+        |body| {
+            body.load_name("i");
+            body.push_literal(1);
+            body.push_opcode(OpCode::Add);
+            body.store_name("i");
+            body.load_name("sum");
+            body.load_name("i");
+            body.push_opcode(OpCode::Add);
+            body.store_name("sum");
+        },
+    );
+    program
+}
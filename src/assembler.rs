@@ -1,21 +1,46 @@
 use std::collections::HashMap;
 
-use crate::{cursor::Cursor, op_codes::OpCode, program::Program};
+use crate::{
+    bigint::BigInt,
+    cursor::Cursor,
+    instruction::Instruction,
+    label::Label,
+    lines::{LineTable, SourceMap},
+    op_codes::OpCode,
+    program::Program,
+};
 
+/// Assembles `input`, recording the line each instruction came from in
+/// [`Program::lines`].
 #[must_use]
 pub fn compile_str(input: &str) -> Program {
-    let tokens = tokenize(input);
-    compile_tokens(tokens)
+    let source = SourceMap::new(input);
+    let mut lines = LineTable::new();
+    let mut program = compile(spanned_tokenize(input), |program, start| {
+        lines.push(program.len(), source.location(start));
+    });
+    lines.truncate(program.len());
+    program.lines = Some(lines);
+    program
 }
 
 #[must_use]
 pub fn compile_tokens<'a, I: Iterator<Item = Token<'a>>>(tokens: I) -> Program {
+    compile(tokens.map(|token| (0, token)), |_, _| ())
+}
+
+/// Assembles tokens paired with their byte offset in the source, calling
+/// `on_token` with each offset before the token's code is pushed.
+fn compile<'a>(
+    mut tokens: impl Iterator<Item = (usize, Token<'a>)>,
+    mut on_token: impl FnMut(&Program, usize),
+) -> Program {
     let mut program = Program::new();
 
-    let mut jumps: HashMap<&str, usize> = HashMap::default();
-    let mut incomplete_jumps: HashMap<&str, Vec<usize>> = HashMap::default();
+    let mut labels: HashMap<&str, Label> = HashMap::default();
 
-    for token in tokens {
+    while let Some((start, token)) = tokens.next() {
+        on_token(&program, start);
         match token {
             Token::Comment | Token::Whitespace => {}
 
@@ -23,55 +48,91 @@ pub fn compile_tokens<'a, I: Iterator<Item = Token<'a>>>(tokens: I) -> Program {
             Token::Sub => program.push_opcode(OpCode::Sub),
             Token::Mul => program.push_opcode(OpCode::Mul),
             Token::Div => program.push_opcode(OpCode::Div),
+            Token::Mod => program.push_opcode(OpCode::Mod),
+            Token::Pow => program.push_opcode(OpCode::Pow),
+            Token::IntDiv => program.push_opcode(OpCode::IntDiv),
+
+            Token::BitAnd => program.push_opcode(OpCode::BitAnd),
+            Token::BitOr => program.push_opcode(OpCode::BitOr),
+            Token::BitXor => program.push_opcode(OpCode::BitXor),
+            Token::BitNot => program.push_opcode(OpCode::BitNot),
+            Token::Shl => program.push_opcode(OpCode::Shl),
+            Token::Shr => program.push_opcode(OpCode::Shr),
 
             Token::Le => program.push_opcode(OpCode::Le),
             Token::Lt => program.push_opcode(OpCode::Lt),
             Token::Ge => program.push_opcode(OpCode::Ge),
             Token::Gt => program.push_opcode(OpCode::Gt),
             Token::Eq => program.push_opcode(OpCode::Eq),
+            Token::Ne => program.push_opcode(OpCode::Ne),
             Token::Not => program.push_opcode(OpCode::UnaryNot),
 
             Token::Int(int) => _ = program.push_literal(int),
+            Token::BigInt(int) => _ = program.push_literal(int),
             Token::Float(float) => _ = program.push_literal(float),
             Token::Str(str) => _ = program.push_literal(str.to_owned()),
 
-            Token::Jump(str) => {
-                let location = jumps.get(str).copied().unwrap_or(0);
-                let jump = program.push_jump(location);
-                if jumps.get(str).is_none() {
-                    incomplete_jumps.entry(str).or_default().push(jump);
-                }
-            }
-
-            Token::OptJump(str) => {
-                let location = jumps.get(str).copied().unwrap_or(0);
-                let jump = program.push_pop_jump_if_false(location);
-                if jumps.get(str).is_none() {
-                    incomplete_jumps.entry(str).or_default().push(jump);
-                }
+            Token::Jump(str)
+            | Token::OptJump(str)
+            | Token::TrueJump(str)
+            | Token::AndJump(str)
+            | Token::OrJump(str) => {
+                let jump_to = match token {
+                    Token::Jump(_) => Program::jump_to,
+                    Token::OptJump(_) => Program::pop_jump_if_false_to,
+                    Token::TrueJump(_) => Program::pop_jump_if_true_to,
+                    Token::AndJump(_) => Program::jump_if_false_or_pop_to,
+                    _ => Program::jump_if_true_or_pop_to,
+                };
+                let label = *labels.entry(str).or_insert_with(|| program.new_label());
+                jump_to(&mut program, label);
             }
 
             Token::Flag(str) => {
-                assert!(!jumps.contains_key(str));
-                jumps.insert(str, program.len());
-                if let Some(to_patch) = incomplete_jumps.get(str) {
-                    for jump in to_patch {
-                        program.patch_jump(*jump);
-                    }
-                    incomplete_jumps.remove(str);
-                }
+                let label = *labels.entry(str).or_insert_with(|| program.new_label());
+                assert!(
+                    program.label_offset(label).is_none(),
+                    "label '{str}' is defined twice"
+                );
+                program.bind(label);
             }
             Token::Keyword("ret") => program.push_opcode(OpCode::Ret),
             Token::Keyword("pop") => program.push_opcode(OpCode::Pop),
             Token::Keyword("swap") => program.push_opcode(OpCode::Swap),
             Token::Keyword("dup") => program.push_opcode(OpCode::Dup),
             Token::Keyword("dup_swap") => program.push_opcode(OpCode::DupSwap),
+            Token::Keyword("rot") => program.push_opcode(OpCode::Rot),
+            Token::Keyword("over") => program.push_opcode(OpCode::Over),
+            Token::Keyword("pick") => {
+                program.push_instruction(Instruction::Pick(depth(&mut tokens)));
+            }
+            Token::Keyword("roll") => {
+                program.push_instruction(Instruction::Roll(depth(&mut tokens)));
+            }
+            Token::Keyword("pop_n") => {
+                program.push_instruction(Instruction::PopN(depth(&mut tokens)));
+            }
             Token::Keyword(keyword) => todo!("{keyword}"),
             Token::End => unreachable!(),
         }
     }
 
-    program
+    program.finish().unwrap_or_else(|err| {
+        let name = labels
+            .iter()
+            .find(|&(_, &label)| label == err.label)
+            .unwrap()
+            .0;
+        panic!("jump to undefined label '{name}'")
+    })
+}
+
+/// The operand of `pick`, `roll` and `pop_n`, which follows the keyword.
+fn depth<'a>(tokens: &mut impl Iterator<Item = (usize, Token<'a>)>) -> u32 {
+    match tokens.next().map(|(_, token)| token) {
+        Some(Token::Int(n)) => u32::try_from(n).unwrap(),
+        token => panic!("expected a stack depth, found {token:?}"),
+    }
 }
 
 #[derive(Debug)]
@@ -80,6 +141,7 @@ pub enum Token<'a> {
     Comment,
 
     Int(i64),
+    BigInt(BigInt),
     Float(f64),
     Str(&'a str),
 
@@ -87,6 +149,16 @@ pub enum Token<'a> {
     Sub,
     Mul,
     Div,
+    Mod,
+    Pow,
+    IntDiv,
+
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
 
     Not,
 
@@ -95,22 +167,38 @@ pub enum Token<'a> {
     Ge,
     Gt,
     Eq,
+    Ne,
 
     Keyword(&'a str),
     Flag(&'a str),
     Jump(&'a str),
     OptJump(&'a str),
+    /// `!?label`, which pops and jumps if the value is truthy.
+    TrueJump(&'a str),
+    /// `&&label`, which jumps if the value is falsy and pops it otherwise.
+    AndJump(&'a str),
+    /// `||label`, which jumps if the value is truthy and pops it otherwise.
+    OrJump(&'a str),
 
     End,
 }
 
-pub fn tokenize(input: &str) -> impl Iterator<Item = Token> {
+pub fn tokenize(input: &str) -> impl Iterator<Item = Token<'_>> {
     let mut cursor = Cursor::new(input);
     std::iter::from_fn(move || cursor.next_token())
         .filter(|tok| !matches!(tok, Token::Whitespace | Token::Comment))
 }
 
-pub fn filter_tokenize(input: &str) -> impl Iterator<Item = Token> {
+fn spanned_tokenize(input: &str) -> impl Iterator<Item = (usize, Token<'_>)> {
+    let mut cursor = Cursor::new(input);
+    std::iter::from_fn(move || {
+        let start = cursor.head;
+        Some((start, cursor.next_token()?))
+    })
+    .filter(|(_, tok)| !matches!(tok, Token::Whitespace | Token::Comment))
+}
+
+pub fn filter_tokenize(input: &str) -> impl Iterator<Item = Token<'_>> {
     tokenize(input).filter(|token| !matches!(token, Token::Whitespace | Token::Comment))
 }
 
@@ -122,8 +210,37 @@ impl<'a> Cursor<'a> {
             '/' if self.peek() == Some('/') => self.line_comment(),
             '+' => Token::Add,
             '-' => Token::Sub,
+            '*' if self.peek() == Some('*') => {
+                self.bump();
+                Token::Pow
+            }
             '*' => Token::Mul,
             '/' => Token::Div,
+            '%' => Token::Mod,
+            '~' if self.peek() == Some('/') => {
+                self.bump();
+                Token::IntDiv
+            }
+            '~' => Token::BitNot,
+            '&' if self.peek() == Some('&') => {
+                self.bump();
+                Token::AndJump(self.parse_ident(self.head))
+            }
+            '&' => Token::BitAnd,
+            '|' if self.peek() == Some('|') => {
+                self.bump();
+                Token::OrJump(self.parse_ident(self.head))
+            }
+            '|' => Token::BitOr,
+            '^' => Token::BitXor,
+            '<' if self.peek() == Some('<') => {
+                self.bump();
+                Token::Shl
+            }
+            '>' if self.peek() == Some('>') => {
+                self.bump();
+                Token::Shr
+            }
 
             '<' if self.peek() == Some('=') => {
                 self.bump();
@@ -136,6 +253,14 @@ impl<'a> Cursor<'a> {
             '<' => Token::Lt,
             '>' => Token::Gt,
             '=' => Token::Eq,
+            '!' if self.peek() == Some('=') => {
+                self.bump();
+                Token::Ne
+            }
+            '!' if self.peek() == Some('?') => {
+                self.bump();
+                Token::TrueJump(self.parse_ident(self.head))
+            }
             '!' => Token::Not,
 
             '0'..='9' => self.parse_num(self.head - 1),
@@ -161,8 +286,10 @@ impl<'a> Cursor<'a> {
             Token::Float(float)
         } else {
             let string = &self.text[start..self.head];
-            let int = string.parse().unwrap();
-            Token::Int(int)
+            match string.parse() {
+                Ok(int) => Token::Int(int),
+                Err(_) => Token::BigInt(string.parse().unwrap()),
+            }
         }
     }
 
@@ -0,0 +1,536 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, Mul, Neg, Sub},
+    str::FromStr,
+};
+
+/// An arbitrary-precision signed integer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct BigInt {
+    negative: bool,
+    /// Little-endian base 2^32 digits of the magnitude, without trailing zeros.
+    limbs: Vec<u32>,
+}
+
+impl BigInt {
+    #[must_use]
+    pub fn from_limbs(negative: bool, mut limbs: Vec<u32>) -> Self {
+        trim(&mut limbs);
+        Self {
+            negative: negative && !limbs.is_empty(),
+            limbs,
+        }
+    }
+    #[must_use]
+    pub fn limbs(&self) -> &[u32] {
+        &self.limbs
+    }
+    #[must_use]
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+    #[must_use]
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+    /// The number of bits in the magnitude.
+    #[must_use]
+    pub fn bit_len(&self) -> u64 {
+        self.limbs.last().map_or(0, |&top| {
+            self.limbs.len() as u64 * 32 - u64::from(top.leading_zeros())
+        })
+    }
+    #[must_use]
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.limbs.len() > 2 {
+            return None;
+        }
+        let low = u64::from(self.limbs.first().copied().unwrap_or(0));
+        let high = u64::from(self.limbs.get(1).copied().unwrap_or(0));
+        let magnitude = high << 32 | low;
+        if self.negative {
+            0i64.checked_sub_unsigned(magnitude)
+        } else {
+            i64::try_from(magnitude).ok()
+        }
+    }
+    #[must_use]
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self
+            .limbs
+            .iter()
+            .rev()
+            .fold(0.0, |acc, &limb| acc * 4_294_967_296.0 + f64::from(limb));
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+    #[must_use]
+    pub fn pow(&self, mut exp: u32) -> Self {
+        let mut base = self.clone();
+        let mut result = Self::from(1i64);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = &result * &base;
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = &base * &base;
+            }
+        }
+        result
+    }
+    /// Converts a finite float without a fractional part exactly.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn from_integral_f64(float: f64) -> Self {
+        debug_assert!(float.is_finite() && float.fract() == 0.0);
+        if float.abs() < 2f64.powi(127) {
+            return Self::from(float as i128);
+        }
+        let bits = float.to_bits();
+        let exponent = (bits >> 52 & 0x7ff) as u32;
+        let mantissa = bits & ((1 << 52) - 1) | 1 << 52;
+        let magnitude = &Self::from(i128::from(mantissa)) * &Self::from(2i64).pow(exponent - 1075);
+        if float < 0.0 {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+    /// Division rounding towards negative infinity, returning the quotient and
+    /// a remainder with the sign of `rhs`, or `None` if `rhs` is zero.
+    #[must_use]
+    pub fn div_mod_floor(&self, rhs: &Self) -> Option<(Self, Self)> {
+        if rhs.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = div_rem_mag(&self.limbs, &rhs.limbs);
+        let quotient = Self::from_limbs(self.negative != rhs.negative, quotient);
+        let remainder = Self::from_limbs(self.negative, remainder);
+        if !remainder.is_zero() && self.negative != rhs.negative {
+            return Some((&quotient - &Self::from(1i64), &remainder + rhs));
+        }
+        Some((quotient, remainder))
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        Self::from(i128::from(value))
+    }
+}
+
+impl From<i128> for BigInt {
+    #[allow(clippy::cast_possible_truncation)]
+    fn from(value: i128) -> Self {
+        let magnitude = value.unsigned_abs();
+        let limbs = (0..4).map(|i| (magnitude >> (32 * i)) as u32).collect();
+        Self::from_limbs(value < 0, limbs)
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.limbs, &other.limbs),
+            (true, true) => cmp_mag(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Neg for BigInt {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Self::from_limbs(!self.negative, self.limbs)
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+    fn add(self, rhs: Self) -> Self::Output {
+        if self.negative == rhs.negative {
+            return BigInt::from_limbs(self.negative, add_mag(&self.limbs, &rhs.limbs));
+        }
+        match cmp_mag(&self.limbs, &rhs.limbs) {
+            Ordering::Less => BigInt::from_limbs(rhs.negative, sub_mag(&rhs.limbs, &self.limbs)),
+            _ => BigInt::from_limbs(self.negative, sub_mag(&self.limbs, &rhs.limbs)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+    fn sub(self, rhs: Self) -> Self::Output {
+        self + &-rhs.clone()
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+    fn mul(self, rhs: Self) -> Self::Output {
+        BigInt::from_limbs(
+            self.negative != rhs.negative,
+            mul_mag(&self.limbs, &rhs.limbs),
+        )
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const CHUNK: u32 = 1_000_000_000;
+
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        let mut chunks = vec![];
+        let mut magnitude = self.limbs.clone();
+        while !magnitude.is_empty() {
+            let (quotient, remainder) = div_rem_small(&magnitude, CHUNK);
+            chunks.push(remainder);
+            magnitude = quotient;
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        write!(f, "{}", chunks.next().unwrap())?;
+        for chunk in chunks {
+            write!(f, "{chunk:09}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseBigIntError;
+
+impl FromStr for BigInt {
+    type Err = ParseBigIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseBigIntError);
+        }
+        let mut limbs = vec![];
+        for digit in digits.bytes() {
+            mul_add_small(&mut limbs, 10, u32::from(digit - b'0'));
+        }
+        Ok(Self::from_limbs(negative, limbs))
+    }
+}
+
+fn trim(limbs: &mut Vec<u32>) {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+}
+
+fn cmp_mag(lhs: &[u32], rhs: &[u32]) -> Ordering {
+    lhs.len()
+        .cmp(&rhs.len())
+        .then_with(|| lhs.iter().rev().cmp(rhs.iter().rev()))
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn add_mag(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let (long, short) = if lhs.len() >= rhs.len() {
+        (lhs, rhs)
+    } else {
+        (rhs, lhs)
+    };
+    let mut result = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, &limb) in long.iter().enumerate() {
+        let sum = u64::from(limb) + u64::from(short.get(i).copied().unwrap_or(0)) + carry;
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+    result.push(carry as u32);
+    trim(&mut result);
+    result
+}
+
+/// Subtracts `rhs` from `lhs`, whose magnitude must not be smaller.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn sub_mag(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(lhs.len());
+    let mut borrow = 0i64;
+    for (i, &limb) in lhs.iter().enumerate() {
+        let mut diff = i64::from(limb) - i64::from(rhs.get(i).copied().unwrap_or(0)) - borrow;
+        borrow = i64::from(diff < 0);
+        if diff < 0 {
+            diff += 1 << 32;
+        }
+        result.push(diff as u32);
+    }
+    trim(&mut result);
+    result
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn mul_mag(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let mut result = vec![0u32; lhs.len() + rhs.len()];
+    for (i, &a) in lhs.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &b) in rhs.iter().enumerate() {
+            let product = u64::from(a) * u64::from(b) + u64::from(result[i + j]) + carry;
+            result[i + j] = product as u32;
+            carry = product >> 32;
+        }
+        result[i + rhs.len()] = carry as u32;
+    }
+    trim(&mut result);
+    result
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn mul_add_small(limbs: &mut Vec<u32>, mul: u32, add: u32) {
+    let mut carry = u64::from(add);
+    for limb in limbs.iter_mut() {
+        let product = u64::from(*limb) * u64::from(mul) + carry;
+        *limb = product as u32;
+        carry = product >> 32;
+    }
+    if carry != 0 {
+        limbs.push(carry as u32);
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn div_rem_small(lhs: &[u32], rhs: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0; lhs.len()];
+    let mut remainder = 0u64;
+    for (i, &limb) in lhs.iter().enumerate().rev() {
+        let current = remainder << 32 | u64::from(limb);
+        quotient[i] = (current / u64::from(rhs)) as u32;
+        remainder = current % u64::from(rhs);
+    }
+    trim(&mut quotient);
+    (quotient, remainder as u32)
+}
+
+/// Truncating division of two magnitudes, one limb of the quotient at a time
+/// (Knuth's Algorithm D).
+#[allow(clippy::cast_possible_truncation)]
+fn div_rem_mag(lhs: &[u32], rhs: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if let [divisor] = rhs {
+        let (quotient, remainder) = div_rem_small(lhs, *divisor);
+        let mut remainder = vec![remainder];
+        trim(&mut remainder);
+        return (quotient, remainder);
+    }
+    if cmp_mag(lhs, rhs) == Ordering::Less {
+        return (vec![], lhs.to_vec());
+    }
+    // Shifting both so the divisor's top bit is set keeps each estimated
+    // quotient limb at most two too large.
+    let shift = rhs.last().unwrap().leading_zeros();
+    let mut divisor = shl_bits(rhs, shift);
+    divisor.pop();
+    let mut remainder = shl_bits(lhs, shift);
+    let n = divisor.len();
+    let (top, second) = (u64::from(divisor[n - 1]), u64::from(divisor[n - 2]));
+    let mut quotient = vec![0; lhs.len() - n + 1];
+    for j in (0..quotient.len()).rev() {
+        let window = &mut remainder[j..=j + n];
+        let numerator = u64::from(window[n]) << 32 | u64::from(window[n - 1]);
+        let (mut estimate, mut rem) = (numerator / top, numerator % top);
+        while estimate > u64::from(u32::MAX)
+            || estimate * second > (rem << 32 | u64::from(window[n - 2]))
+        {
+            estimate -= 1;
+            rem += top;
+            if rem > u64::from(u32::MAX) {
+                break;
+            }
+        }
+        if sub_mul(window, &divisor, estimate) {
+            estimate -= 1;
+            add_back(window, &divisor);
+        }
+        quotient[j] = estimate as u32;
+    }
+    remainder.truncate(n);
+    let mut remainder = (0..n)
+        .map(|i| {
+            let wide = u64::from(remainder.get(i + 1).copied().unwrap_or(0)) << 32
+                | u64::from(remainder[i]);
+            (wide >> shift) as u32
+        })
+        .collect();
+    trim(&mut quotient);
+    trim(&mut remainder);
+    (quotient, remainder)
+}
+
+/// Shifts a magnitude left by fewer than 32 bits, always adding a top limb.
+#[allow(clippy::cast_possible_truncation)]
+fn shl_bits(limbs: &[u32], shift: u32) -> Vec<u32> {
+    let mut result = Vec::with_capacity(limbs.len() + 1);
+    let mut carry = 0;
+    for &limb in limbs {
+        let wide = u64::from(limb) << shift | carry;
+        result.push(wide as u32);
+        carry = wide >> 32;
+    }
+    result.push(carry as u32);
+    result
+}
+
+/// Subtracts `divisor * factor` from `window`, which is one limb longer than
+/// `divisor`, returning whether the result went negative.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn sub_mul(window: &mut [u32], divisor: &[u32], factor: u64) -> bool {
+    let mut carry = 0u64;
+    let mut borrow = 0i64;
+    for (limb, &digit) in window.iter_mut().zip(divisor) {
+        let product = factor * u64::from(digit) + carry;
+        carry = product >> 32;
+        let diff = i64::from(*limb) - i64::from(product as u32) - borrow;
+        *limb = diff as u32;
+        borrow = i64::from(diff < 0);
+    }
+    let top = &mut window[divisor.len()];
+    let diff = i64::from(*top) - i64::from(carry as u32) - borrow;
+    *top = diff as u32;
+    diff < 0
+}
+
+/// Adds `divisor` back to a `window` that `sub_mul` left negative.
+#[allow(clippy::cast_possible_truncation)]
+fn add_back(window: &mut [u32], divisor: &[u32]) {
+    let mut carry = 0u64;
+    for (limb, &digit) in window.iter_mut().zip(divisor) {
+        let sum = u64::from(*limb) + u64::from(digit) + carry;
+        *limb = sum as u32;
+        carry = sum >> 32;
+    }
+    let top = &mut window[divisor.len()];
+    *top = top.wrapping_add(carry as u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(s: &str) -> BigInt {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_display() {
+        for s in [
+            "0",
+            "1",
+            "-1",
+            "4294967296",
+            "-123456789012345678901234567890",
+        ] {
+            assert_eq!(big(s).to_string(), s);
+        }
+        assert_eq!(big("-0"), BigInt::from(0i64));
+        assert_eq!(big("000123").to_string(), "123");
+        assert!("".parse::<BigInt>().is_err());
+        assert!("12a".parse::<BigInt>().is_err());
+    }
+    #[test]
+    fn test_i64_round_trip() {
+        for int in [0, 1, -1, i64::MAX, i64::MIN] {
+            assert_eq!(BigInt::from(int).to_i64(), Some(int));
+        }
+        assert_eq!(big("9223372036854775808").to_i64(), None);
+        assert_eq!(big("-9223372036854775809").to_i64(), None);
+    }
+    #[test]
+    fn test_arithmetic() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+        assert_eq!((&a + &b).to_string(), "-864197532086419753208641975320");
+        assert_eq!((&a - &b).to_string(), "1111111110111111111011111111100");
+        assert_eq!(
+            (&a * &b).to_string(),
+            "-121932631137021795226185032733622923332237463801111263526900"
+        );
+        assert_eq!(
+            BigInt::from(2i64).pow(100).to_string(),
+            "1267650600228229401496703205376"
+        );
+        assert_eq!(BigInt::from(-3i64).pow(3), BigInt::from(-27i64));
+    }
+    #[test]
+    fn test_div_mod_floor() {
+        let a = big("1267650600228229401496703205377");
+        let (q, r) = a.div_mod_floor(&BigInt::from(2i64).pow(64)).unwrap();
+        assert_eq!(q, BigInt::from(2i64).pow(36));
+        assert_eq!(r, BigInt::from(1i64));
+
+        let (q, r) = (-a.clone())
+            .div_mod_floor(&big("10000000000000000000000"))
+            .unwrap();
+        assert_eq!(q.to_string(), "-126765061");
+        assert_eq!(r.to_string(), "9771770598503296794623");
+
+        assert_eq!(
+            BigInt::from(-7i64).div_mod_floor(&BigInt::from(2i64)),
+            Some((BigInt::from(-4i64), BigInt::from(1i64)))
+        );
+        assert_eq!(a.div_mod_floor(&BigInt::default()), None);
+    }
+    #[test]
+    fn test_div_mod_floor_large() {
+        // Divisors with a top limb of 1 and of all ones, and remainders that
+        // need the estimated quotient limb corrected.
+        let lhs = &BigInt::from(7i64).pow(1000) - &BigInt::from(1i64);
+        for rhs in [
+            BigInt::from(3i64).pow(300),
+            &BigInt::from(2i64).pow(64) + &BigInt::from(1i64),
+            &BigInt::from(2i64).pow(128) - &BigInt::from(1i64),
+            big("340282366920938463444927863358058659840"),
+            -BigInt::from(11i64).pow(250),
+        ] {
+            let (q, r) = lhs.div_mod_floor(&rhs).unwrap();
+            assert_eq!(&(&q * &rhs) + &r, lhs);
+            assert!(r.is_zero() || r.is_negative() == rhs.is_negative());
+            assert!(cmp_mag(&r.limbs, &rhs.limbs) == Ordering::Less);
+        }
+        let (q, r) = BigInt::from(2i64)
+            .pow(4000)
+            .div_mod_floor(&BigInt::from(2i64).pow(1000))
+            .unwrap();
+        assert_eq!((q, r), (BigInt::from(2i64).pow(3000), BigInt::default()));
+    }
+    #[test]
+    fn test_from_integral_f64() {
+        assert_eq!(BigInt::from_integral_f64(-3.0), BigInt::from(-3i64));
+        assert_eq!(
+            BigInt::from_integral_f64(2f64.powi(200)),
+            BigInt::from(2i64).pow(200)
+        );
+        assert_eq!(
+            BigInt::from_integral_f64(-(2f64.powi(130) + 2f64.powi(90))),
+            -(&BigInt::from(2i64).pow(130) + &BigInt::from(2i64).pow(90))
+        );
+    }
+    #[test]
+    fn test_ordering() {
+        assert!(big("-100000000000000000000") < BigInt::from(-1i64));
+        assert!(BigInt::from(-1i64) < BigInt::from(0i64));
+        assert!(big("100000000000000000000") > BigInt::from(i64::MAX));
+        assert!(big("-5") > big("-6"));
+    }
+}
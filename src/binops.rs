@@ -1,71 +1,566 @@
+//! Arithmetic on [`Value`]s.
+//!
+//! Operations that produce an `Int` raise [`BinOpError::ZeroDivision`] when
+//! dividing by zero, while operations that produce a `Float` follow IEEE 754
+//! (`1 / 0` is `inf`, `1.0 % 0` is `NaN`).
+//!
+//! `%` and `~/` round towards negative infinity, so the result of `%` takes
+//! the sign of the divisor: `-7 % 3 == 2` and `7 ~/ -2 == -4`.
+//!
+//! Integer arithmetic never overflows: results outside the range of `i64` are
+//! promoted to [`Value::BigInt`] and demoted back to `Int` once they fit again.
+//! Results too large to build, integers of more than about 39,000 digits or
+//! strings of more than 16 MiB, raise [`BinOpError::Overflow`] instead.
+//!
+//! `str * n` repeats the string, giving the empty string when `n` is below 1.
+//!
+//! Comparisons between numbers are exact across `Int`, `BigInt` and `Float`,
+//! so `1 == 1.0` and `9007199254740993 > 9007199254740992.0`. Strings compare
+//! lexicographically by code point. Ordering a string against a number is a
+//! type error, while `==` between them is simply false. Following IEEE 754,
+//! every comparison with `NaN` is false except `!=`, which is true.
+//!
+//! Bitwise operators are only defined for integers in the range of `i64` and
+//! act on their 64-bit two's complement representation. Shifting by a
+//! negative amount is an error, while shifting by 64 or more shifts every bit
+//! out: `<<` gives `0` and `>>` gives `0` or `-1` depending on the sign.
 #![allow(clippy::cast_precision_loss)]
 #![allow(clippy::cast_possible_truncation)]
 #![allow(clippy::cast_sign_loss)]
 
 use std::{
-    borrow::Cow,
-    ops::{Add, Div, Mul, Sub},
+    cmp::Ordering,
+    fmt,
+    ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Rem, Shl, Shr, Sub},
+    rc::Rc,
 };
 
-use crate::value::Value;
+use crate::{bigint::BigInt, op_codes::OpCode, value::Value};
+
+pub type BinOpResult = Result<Value, BinOpError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinOpError {
+    ZeroDivision,
+    Overflow,
+    NegativeShift,
+    Incomparable {
+        lhs: &'static str,
+        rhs: &'static str,
+    },
+    Unsupported {
+        op: &'static str,
+        lhs: &'static str,
+        rhs: &'static str,
+    },
+    UnsupportedUnary {
+        op: &'static str,
+        operand: &'static str,
+    },
+}
+
+impl BinOpError {
+    fn unsupported(op: &'static str, lhs: &Value, rhs: &Value) -> Self {
+        Self::Unsupported {
+            op,
+            lhs: lhs.type_name(),
+            rhs: rhs.type_name(),
+        }
+    }
+}
+
+impl fmt::Display for BinOpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroDivision => write!(f, "integer division by zero"),
+            Self::Overflow => write!(f, "integer overflow"),
+            Self::NegativeShift => write!(f, "negative shift amount"),
+            Self::Incomparable { lhs, rhs } => write!(f, "cannot compare {lhs} and {rhs}"),
+            Self::Unsupported { op, lhs, rhs } => {
+                write!(f, "unsupported operand types for {op}: {lhs} and {rhs}")
+            }
+            Self::UnsupportedUnary { op, operand } => {
+                write!(f, "unsupported operand type for {op}: {operand}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BinOpError {}
+
+/// The function implementing the binary operator `op`, shared by the VM and
+/// the constant folder so that both evaluate operators the same way.
+#[must_use]
+pub fn binary_op(op: OpCode) -> Option<fn(Value, Value) -> BinOpResult> {
+    let func: fn(Value, Value) -> BinOpResult = match op {
+        OpCode::Add => Value::add,
+        OpCode::Sub => Value::sub,
+        OpCode::Mul => Value::mul,
+        OpCode::Div => Value::div,
+        OpCode::Mod => Value::rem,
+        OpCode::Pow => Value::pow,
+        OpCode::IntDiv => Value::floor_div,
+
+        OpCode::BitAnd => Value::bitand,
+        OpCode::BitOr => Value::bitor,
+        OpCode::BitXor => Value::bitxor,
+        OpCode::Shl => Value::shl,
+        OpCode::Shr => Value::shr,
+
+        OpCode::Le => |lhs, rhs| cmp(&lhs, &rhs, Ordering::is_le),
+        OpCode::Lt => |lhs, rhs| cmp(&lhs, &rhs, Ordering::is_lt),
+        OpCode::Ge => |lhs, rhs| cmp(&lhs, &rhs, Ordering::is_ge),
+        OpCode::Gt => |lhs, rhs| cmp(&lhs, &rhs, Ordering::is_gt),
+        OpCode::Eq => |lhs, rhs| Ok(lhs.equals(&rhs).into()),
+        OpCode::Ne => |lhs, rhs| Ok((!lhs.equals(&rhs)).into()),
+        _ => return None,
+    };
+    Some(func)
+}
+
+/// The function implementing the unary operator `op`.
+#[must_use]
+pub fn unary_op(op: OpCode) -> Option<fn(Value) -> BinOpResult> {
+    let func: fn(Value) -> BinOpResult = match op {
+        OpCode::UnaryNot => |val| Ok((!bool::from(&val)).into()),
+        OpCode::BitNot => Value::bit_not,
+        _ => return None,
+    };
+    Some(func)
+}
+
+/// An ordering operator, which is false whenever an operand is `NaN`.
+fn cmp(lhs: &Value, rhs: &Value, func: fn(Ordering) -> bool) -> BinOpResult {
+    Ok(Value::from(lhs.compare(rhs)?.is_some_and(func)))
+}
 
 impl Add for Value {
-    type Output = Self;
+    type Output = BinOpResult;
     fn add(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Self::Int(lhs), Self::Int(rhs)) => Self::Int(lhs + rhs),
-            (Self::Float(lhs), Self::Int(rhs)) => Self::Float(lhs + rhs as f64),
-            (Self::Int(lhs), Self::Float(rhs)) => Self::Float(lhs as f64 + rhs),
-            (Self::Float(lhs), Self::Float(rhs)) => Self::Float(lhs + rhs),
-            (Self::Str(lhs), Self::Str(rhs)) => Self::Str(lhs + rhs),
-            (lhs, rhs) => todo!("{lhs} + {rhs}"),
+        Ok(match Operands::new(self, rhs) {
+            Operands::Ints(lhs, rhs) => lhs.checked_add(rhs).map_or_else(
+                || Self::from(&BigInt::from(lhs) + &BigInt::from(rhs)),
+                Self::Int,
+            ),
+            Operands::BigInts(lhs, rhs) => return limit(&lhs + &rhs),
+            Operands::Floats(lhs, rhs) => Self::Float(lhs + rhs),
+            Operands::Other(Self::Str(mut lhs), Self::Str(rhs)) => {
+                if let Some(str) = Rc::get_mut(&mut lhs) {
+                    str.push_str(&rhs);
+                    Self::Str(lhs)
+                } else {
+                    Self::from([lhs.as_str(), &rhs].concat())
+                }
+            }
+            Operands::Other(lhs, rhs) => return Err(BinOpError::unsupported("+", &lhs, &rhs)),
+        })
+    }
+}
+
+impl Value {
+    /// `+` that updates `self` in place, appending to an unshared string
+    /// without copying it. `self` is left unchanged if `+` fails.
+    /// # Errors
+    /// Fails like `+`.
+    pub fn add_in_place(&mut self, rhs: Self) -> Result<(), BinOpError> {
+        if let (Self::Str(lhs), Self::Str(rhs)) = (&mut *self, &rhs) {
+            Rc::make_mut(lhs).push_str(rhs);
+            return Ok(());
         }
+        *self = (self.clone() + rhs)?;
+        Ok(())
     }
 }
 
 impl Sub for Value {
-    type Output = Self;
+    type Output = BinOpResult;
     fn sub(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Self::Int(lhs), Self::Int(rhs)) => Self::Int(lhs - rhs),
-            (Self::Float(lhs), Self::Int(rhs)) => Self::Float(lhs - rhs as f64),
-            (Self::Int(lhs), Self::Float(rhs)) => Self::Float(lhs as f64 - rhs),
-            (Self::Float(lhs), Self::Float(rhs)) => Self::Float(lhs - rhs),
-            (lhs, rhs) => todo!("{lhs} - {rhs}"),
-        }
+        Ok(match Operands::new(self, rhs) {
+            Operands::Ints(lhs, rhs) => lhs.checked_sub(rhs).map_or_else(
+                || Self::from(&BigInt::from(lhs) - &BigInt::from(rhs)),
+                Self::Int,
+            ),
+            Operands::BigInts(lhs, rhs) => return limit(&lhs - &rhs),
+            Operands::Floats(lhs, rhs) => Self::Float(lhs - rhs),
+            Operands::Other(lhs, rhs) => return Err(BinOpError::unsupported("-", &lhs, &rhs)),
+        })
     }
 }
 
 impl Mul for Value {
-    type Output = Self;
+    type Output = BinOpResult;
     fn mul(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Self::Int(lhs), Self::Int(rhs)) => Self::Int(lhs * rhs),
-            (Self::Float(lhs), Self::Int(rhs)) => Self::Float(lhs * rhs as f64),
-            (Self::Int(lhs), Self::Float(rhs)) => Self::Float(lhs as f64 * rhs),
-            (Self::Float(lhs), Self::Float(rhs)) => Self::Float(lhs * rhs),
-            (Self::Str(str), Self::Int(int)) | (Self::Int(int), Self::Str(str)) => {
-                if int.is_positive() {
-                    return Value::Str(Cow::Owned(str.repeat(int as usize)));
+        Ok(match Operands::new(self, rhs) {
+            Operands::Ints(lhs, rhs) => lhs.checked_mul(rhs).map_or_else(
+                || Self::from(&BigInt::from(lhs) * &BigInt::from(rhs)),
+                Self::Int,
+            ),
+            // The product has at least one limb less than its operands together.
+            Operands::BigInts(lhs, rhs)
+                if (lhs.limbs().len() + rhs.limbs().len()) as u64 > MAX_LIMBS + 1 =>
+            {
+                return Err(BinOpError::Overflow)
+            }
+            Operands::BigInts(lhs, rhs) => return limit(&lhs * &rhs),
+            Operands::Floats(lhs, rhs) => Self::Float(lhs * rhs),
+            Operands::Other(Self::Str(str), Self::Int(int))
+            | Operands::Other(Self::Int(int), Self::Str(str)) => return repeat(&str, int),
+            Operands::Other(Self::Str(_), Self::BigInt(int))
+            | Operands::Other(Self::BigInt(int), Self::Str(_)) => {
+                if !int.is_negative() {
+                    return Err(BinOpError::Overflow);
                 }
-                Value::Str(str)
+                Value::from("")
             }
-            (lhs, rhs) => todo!("{lhs} - {rhs}"),
-        }
+            Operands::Other(lhs, rhs) => return Err(BinOpError::unsupported("*", &lhs, &rhs)),
+        })
     }
 }
 
 impl Div for Value {
-    type Output = Self;
+    type Output = BinOpResult;
     fn div(self, rhs: Self) -> Self::Output {
+        Ok(match Operands::new(self, rhs) {
+            Operands::Ints(lhs, rhs) => Self::Float(lhs as f64 / rhs as f64),
+            Operands::BigInts(lhs, rhs) => Self::Float(lhs.to_f64() / rhs.to_f64()),
+            Operands::Floats(lhs, rhs) => Self::Float(lhs / rhs),
+            Operands::Other(lhs, rhs) => return Err(BinOpError::unsupported("/", &lhs, &rhs)),
+        })
+    }
+}
+
+impl Rem for Value {
+    type Output = BinOpResult;
+    fn rem(self, rhs: Self) -> Self::Output {
+        Ok(match Operands::new(self, rhs) {
+            Operands::Ints(_, 0) => return Err(BinOpError::ZeroDivision),
+            Operands::Ints(lhs, rhs) => Self::Int(floor_rem(lhs, rhs)),
+            Operands::BigInts(lhs, rhs) => {
+                let (_, rem) = lhs.div_mod_floor(&rhs).ok_or(BinOpError::ZeroDivision)?;
+                Self::from(rem)
+            }
+            Operands::Floats(lhs, rhs) => Self::Float(floor_rem_f64(lhs, rhs)),
+            Operands::Other(lhs, rhs) => return Err(BinOpError::unsupported("%", &lhs, &rhs)),
+        })
+    }
+}
+
+impl BitAnd for Value {
+    type Output = BinOpResult;
+    fn bitand(self, rhs: Self) -> Self::Output {
+        let (lhs, rhs) = bitwise_operands("&", self, rhs)?;
+        Ok(Self::Int(lhs & rhs))
+    }
+}
+
+impl BitOr for Value {
+    type Output = BinOpResult;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        let (lhs, rhs) = bitwise_operands("|", self, rhs)?;
+        Ok(Self::Int(lhs | rhs))
+    }
+}
+
+impl BitXor for Value {
+    type Output = BinOpResult;
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        let (lhs, rhs) = bitwise_operands("^", self, rhs)?;
+        Ok(Self::Int(lhs ^ rhs))
+    }
+}
+
+impl Shl for Value {
+    type Output = BinOpResult;
+    fn shl(self, rhs: Self) -> Self::Output {
+        match bitwise_operands("<<", self, rhs)? {
+            (_, rhs) if rhs < 0 => Err(BinOpError::NegativeShift),
+            (_, 64..) => Ok(Self::Int(0)),
+            (lhs, rhs) => Ok(Self::Int(lhs << rhs)),
+        }
+    }
+}
+
+impl Shr for Value {
+    type Output = BinOpResult;
+    fn shr(self, rhs: Self) -> Self::Output {
+        match bitwise_operands(">>", self, rhs)? {
+            (_, rhs) if rhs < 0 => Err(BinOpError::NegativeShift),
+            (lhs, rhs) => Ok(Self::Int(lhs >> rhs.min(63))),
+        }
+    }
+}
+
+impl Value {
+    /// Bitwise complement, the `~` operator.
+    /// # Errors
+    /// Fails if the operand is not an `Int`.
+    pub fn bit_not(self) -> BinOpResult {
+        match self {
+            Self::Int(int) => Ok(Self::Int(!int)),
+            Self::BigInt(_) => Err(BinOpError::Overflow),
+            operand => Err(BinOpError::UnsupportedUnary {
+                op: "~",
+                operand: operand.type_name(),
+            }),
+        }
+    }
+
+    /// Floor division, the `~/` operator.
+    /// # Errors
+    /// Fails on integer division by zero or if either operand is not a number.
+    pub fn floor_div(self, rhs: Self) -> BinOpResult {
+        Ok(match Operands::new(self, rhs) {
+            Operands::Ints(_, 0) => return Err(BinOpError::ZeroDivision),
+            // Only `i64::MIN ~/ -1` leaves the range of `i64`.
+            Operands::Ints(lhs, rhs) => {
+                floor_div(lhs, rhs).map_or_else(|| Self::from(-BigInt::from(lhs)), Self::Int)
+            }
+            Operands::BigInts(lhs, rhs) => {
+                let (quotient, _) = lhs.div_mod_floor(&rhs).ok_or(BinOpError::ZeroDivision)?;
+                Self::from(quotient)
+            }
+            Operands::Floats(lhs, rhs) => Self::Float((lhs / rhs).floor()),
+            Operands::Other(lhs, rhs) => return Err(BinOpError::unsupported("~/", &lhs, &rhs)),
+        })
+    }
+
+    /// Exponentiation, the `**` operator.
+    ///
+    /// An `Int` raised to a negative `Int` produces a `Float`, so `2 ** -1 == 0.5`.
+    /// # Errors
+    /// Fails if either operand is not a number, or if an integer result would
+    /// have more than `MAX_LIMBS` limbs.
+    pub fn pow(self, rhs: Self) -> BinOpResult {
+        Ok(match Operands::new(self, rhs) {
+            Operands::Ints(lhs, rhs) if rhs < 0 => Self::Float((lhs as f64).powf(rhs as f64)),
+            Operands::Ints(lhs, rhs) => match int_pow(lhs, rhs) {
+                Some(int) => Self::Int(int),
+                None => return big_pow(&BigInt::from(lhs), &BigInt::from(rhs)),
+            },
+            Operands::BigInts(lhs, rhs) if rhs.is_negative() => {
+                Self::Float(lhs.to_f64().powf(rhs.to_f64()))
+            }
+            Operands::BigInts(lhs, rhs) => return big_pow(&lhs, &rhs),
+            Operands::Floats(lhs, rhs) => Self::Float(lhs.powf(rhs)),
+            Operands::Other(lhs, rhs) => return Err(BinOpError::unsupported("**", &lhs, &rhs)),
+        })
+    }
+
+    fn as_bigint(&self) -> Option<BigInt> {
+        match self {
+            Self::Int(int) => Some(BigInt::from(*int)),
+            Self::BigInt(int) => Some(int.clone()),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Int(int) => Some(*int as f64),
+            Self::BigInt(int) => Some(int.to_f64()),
+            Self::Float(float) => Some(*float),
+            Self::Str(_) => None,
+        }
+    }
+}
+
+impl Value {
+    /// Orders two values for `<`, `<=`, `>` and `>=`, or returns `None` if
+    /// either is `NaN`.
+    /// # Errors
+    /// Fails if a string is compared against a number.
+    pub fn compare(&self, rhs: &Self) -> Result<Option<Ordering>, BinOpError> {
         match (self, rhs) {
-            (Self::Int(lhs), Self::Int(rhs)) => Self::Float(lhs as f64 / rhs as f64),
-            (Self::Float(lhs), Self::Int(rhs)) => Self::Float(lhs / rhs as f64),
-            (Self::Int(lhs), Self::Float(rhs)) => Self::Float(lhs as f64 / rhs),
-            (Self::Float(lhs), Self::Float(rhs)) => Self::Float(lhs / rhs),
-            (lhs, rhs) => todo!("{lhs} - {rhs}"),
+            (Self::Int(lhs), Self::Int(rhs)) => Ok(Some(lhs.cmp(rhs))),
+            (Self::Float(lhs), Self::Float(rhs)) => Ok(lhs.partial_cmp(rhs)),
+            (Self::Str(lhs), Self::Str(rhs)) => Ok(Some(lhs.cmp(rhs))),
+            (Self::Int(_) | Self::BigInt(_), Self::Int(_) | Self::BigInt(_)) => {
+                Ok(self.as_bigint().cmp(&rhs.as_bigint()).into())
+            }
+            (Self::Int(_) | Self::BigInt(_), Self::Float(float)) => Ok(cmp_int_float(self, *float)),
+            (Self::Float(float), Self::Int(_) | Self::BigInt(_)) => {
+                Ok(cmp_int_float(rhs, *float).map(Ordering::reverse))
+            }
+            (lhs, rhs) => Err(BinOpError::Incomparable {
+                lhs: lhs.type_name(),
+                rhs: rhs.type_name(),
+            }),
         }
     }
+
+    /// Equality for `==` and `!=`. Numbers are equal if they have the same
+    /// value regardless of type, and are never equal to a string.
+    #[must_use]
+    pub fn equals(&self, rhs: &Self) -> bool {
+        self.compare(rhs) == Ok(Some(Ordering::Equal))
+    }
+
+    /// A total order over all values, for sorting.
+    ///
+    /// Numbers are ordered by value with `NaN` after every other number, and
+    /// all numbers sort before strings. Numbers with the same value, such as
+    /// `1` and `1.0`, are equal.
+    #[must_use]
+    pub fn total_cmp(&self, rhs: &Self) -> Ordering {
+        match (self, rhs) {
+            (Self::Str(lhs), Self::Str(rhs)) => lhs.cmp(rhs),
+            (Self::Str(_), _) => Ordering::Greater,
+            (_, Self::Str(_)) => Ordering::Less,
+            (lhs, rhs) => lhs
+                .compare(rhs)
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| lhs.is_nan().cmp(&rhs.is_nan())),
+        }
+    }
+
+    fn is_nan(&self) -> bool {
+        matches!(self, Self::Float(float) if float.is_nan())
+    }
+}
+
+/// Compares an `Int` or `BigInt` with a float without rounding either.
+#[allow(clippy::cast_possible_truncation)]
+fn cmp_int_float(int: &Value, float: f64) -> Option<Ordering> {
+    if float.is_nan() {
+        return None;
+    }
+    if float.is_infinite() {
+        return Some(if float > 0.0 {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        });
+    }
+    let trunc = float.trunc();
+    let ordering = match int {
+        Value::Int(int) if trunc.abs() < 2f64.powi(63) => int.cmp(&(trunc as i64)),
+        int => int.as_bigint()?.cmp(&BigInt::from_integral_f64(trunc)),
+    };
+    // Equal integer parts are decided by the fractional part of the float.
+    ordering
+        .then_with(|| 0.0.partial_cmp(&float.fract()).unwrap())
+        .into()
+}
+
+/// The operands of an arithmetic operator, promoted to a common type.
+enum Operands {
+    Ints(i64, i64),
+    BigInts(BigInt, BigInt),
+    Floats(f64, f64),
+    Other(Value, Value),
+}
+
+impl Operands {
+    fn new(lhs: Value, rhs: Value) -> Self {
+        match (lhs, rhs) {
+            (Value::Int(lhs), Value::Int(rhs)) => Self::Ints(lhs, rhs),
+            (Value::Float(lhs), Value::Float(rhs)) => Self::Floats(lhs, rhs),
+            (lhs, rhs) => {
+                if let (Some(lhs), Some(rhs)) = (lhs.as_bigint(), rhs.as_bigint()) {
+                    return Self::BigInts(lhs, rhs);
+                }
+                if let (Some(lhs), Some(rhs)) = (lhs.as_f64(), rhs.as_f64()) {
+                    return Self::Floats(lhs, rhs);
+                }
+                Self::Other(lhs, rhs)
+            }
+        }
+    }
+}
+
+/// Unpacks the operands of a bitwise operator, which only accepts integers that
+/// fit in an `i64`.
+fn bitwise_operands(op: &'static str, lhs: Value, rhs: Value) -> Result<(i64, i64), BinOpError> {
+    match (lhs, rhs) {
+        (Value::Int(lhs), Value::Int(rhs)) => Ok((lhs, rhs)),
+        (Value::Int(_) | Value::BigInt(_), Value::Int(_) | Value::BigInt(_)) => {
+            Err(BinOpError::Overflow)
+        }
+        (lhs, rhs) => Err(BinOpError::unsupported(op, &lhs, &rhs)),
+    }
+}
+
+/// The longest string, in bytes, that `*` may build.
+pub(crate) const MAX_STR_LEN: usize = 1 << 24;
+
+/// Repeats `str` `count` times, giving the empty string for a count below 1.
+fn repeat(str: &str, count: i64) -> BinOpResult {
+    let count = usize::try_from(count).unwrap_or(0);
+    match str.len().checked_mul(count) {
+        Some(len) if len <= MAX_STR_LEN => Ok(Value::from(str.repeat(count))),
+        _ => Err(BinOpError::Overflow),
+    }
+}
+
+/// The most 32-bit limbs an integer result may have, about 39,000 decimal
+/// digits. Larger results fail with [`BinOpError::Overflow`], where possible
+/// before they are computed.
+pub(crate) const MAX_LIMBS: u64 = 1 << 12;
+
+/// Converts an integer result, failing if it has more than `MAX_LIMBS` limbs.
+fn limit(int: BigInt) -> BinOpResult {
+    if int.limbs().len() as u64 > MAX_LIMBS {
+        return Err(BinOpError::Overflow);
+    }
+    Ok(Value::from(int))
+}
+
+/// Raises `base` to a non-negative `exp`.
+fn big_pow(base: &BigInt, exp: &BigInt) -> BinOpResult {
+    if exp.is_zero() {
+        return Ok(Value::Int(1));
+    }
+    let odd = exp.limbs()[0] & 1 == 1;
+    match base.to_i64() {
+        Some(0 | 1) => return Ok(Value::from(base.clone())),
+        Some(-1) => return Ok(Value::Int(if odd { -1 } else { 1 })),
+        _ => (),
+    }
+    // The result has more than `(bit_len(base) - 1) * exp` bits, and at most
+    // twice that since `base` has at least two bits.
+    match exp.to_i64().and_then(|exp| u32::try_from(exp).ok()) {
+        Some(exp) if (base.bit_len() - 1) * u64::from(exp) < MAX_LIMBS * 32 => limit(base.pow(exp)),
+        _ => Err(BinOpError::Overflow),
+    }
+}
+
+fn floor_div(lhs: i64, rhs: i64) -> Option<i64> {
+    let quotient = lhs.checked_div(rhs)?;
+    if lhs % rhs != 0 && (lhs < 0) != (rhs < 0) {
+        Some(quotient - 1)
+    } else {
+        Some(quotient)
+    }
+}
+
+fn floor_rem(lhs: i64, rhs: i64) -> i64 {
+    // `i64::MIN % -1` is zero, but `%` reports it as an overflow.
+    let rem = lhs.wrapping_rem(rhs);
+    if rem != 0 && (rem < 0) != (rhs < 0) {
+        rem + rhs
+    } else {
+        rem
+    }
+}
+
+fn floor_rem_f64(lhs: f64, rhs: f64) -> f64 {
+    let rem = lhs % rhs;
+    if rem != 0.0 && (rem < 0.0) != (rhs < 0.0) {
+        rem + rhs
+    } else {
+        rem
+    }
+}
+
+fn int_pow(base: i64, exp: i64) -> Option<i64> {
+    match base {
+        _ if exp == 0 => Some(1),
+        0 | 1 => Some(base),
+        -1 if exp % 2 == 0 => Some(1),
+        -1 => Some(-1),
+        _ => base.checked_pow(u32::try_from(exp).ok()?),
+    }
 }
 
 #[cfg(test)]
@@ -74,30 +569,350 @@ mod tests {
 
     #[test]
     fn test_add() {
-        assert_eq!(Value::Int(1) + Value::Int(2), Value::Int(3));
-        assert_eq!(Value::Float(1.5) + Value::Int(2), Value::Float(3.5));
-        assert_eq!(Value::Int(1) + Value::Float(0.5), Value::Float(1.5));
-        assert_eq!(Value::Float(1.5) + Value::Float(2.5), Value::Float(4.0));
+        assert_eq!(Value::Int(1) + Value::Int(2), Ok(Value::Int(3)));
+        assert_eq!(Value::Float(1.5) + Value::Int(2), Ok(Value::Float(3.5)));
+        assert_eq!(Value::Int(1) + Value::Float(0.5), Ok(Value::Float(1.5)));
+        assert_eq!(Value::Float(1.5) + Value::Float(2.5), Ok(Value::Float(4.0)));
+    }
+    #[test]
+    fn test_str_concat() {
+        assert_eq!(Value::from("ab") + Value::from("c"), Ok(Value::from("abc")));
+
+        // A shared string is copied rather than appended to in place.
+        let shared = Value::from("ab");
+        assert_eq!(shared.clone() + Value::from("c"), Ok(Value::from("abc")));
+        assert_eq!(shared, Value::from("ab"));
+        assert_eq!(Value::Int(2) * shared.clone(), Ok(Value::from("abab")));
+    }
+    #[test]
+    fn test_add_in_place() {
+        let mut value = Value::from("ab");
+        let shared = value.clone();
+        value.add_in_place(Value::from("c")).unwrap();
+        assert_eq!((value, shared), (Value::from("abc"), Value::from("ab")));
+
+        let mut value = Value::Int(1);
+        value.add_in_place(Value::Int(2)).unwrap();
+        assert_eq!(value, Value::Int(3));
+        let err = value.add_in_place(Value::from("a"));
+        assert_eq!(
+            err,
+            Err(BinOpError::unsupported("+", &value, &Value::from("a")))
+        );
+        assert_eq!(value, Value::Int(3));
     }
     #[test]
     fn test_sub() {
-        assert_eq!(Value::Int(5) - Value::Int(3), Value::Int(2));
-        assert_eq!(Value::Float(3.5) - Value::Int(1), Value::Float(2.5));
-        assert_eq!(Value::Int(1) - Value::Float(0.5), Value::Float(0.5));
-        assert_eq!(Value::Float(4.5) - Value::Float(2.5), Value::Float(2.0));
+        assert_eq!(Value::Int(5) - Value::Int(3), Ok(Value::Int(2)));
+        assert_eq!(Value::Float(3.5) - Value::Int(1), Ok(Value::Float(2.5)));
+        assert_eq!(Value::Int(1) - Value::Float(0.5), Ok(Value::Float(0.5)));
+        assert_eq!(Value::Float(4.5) - Value::Float(2.5), Ok(Value::Float(2.0)));
     }
     #[test]
     fn test_mul() {
-        assert_eq!(Value::Int(5) * Value::Int(3), Value::Int(15));
-        assert_eq!(Value::Float(3.5) * Value::Int(2), Value::Float(7.0));
-        assert_eq!(Value::Int(1) * Value::Float(0.5), Value::Float(0.5));
-        assert_eq!(Value::Float(5.0) * Value::Float(2.5), Value::Float(12.5));
+        assert_eq!(Value::Int(5) * Value::Int(3), Ok(Value::Int(15)));
+        assert_eq!(Value::Float(3.5) * Value::Int(2), Ok(Value::Float(7.0)));
+        assert_eq!(Value::Int(1) * Value::Float(0.5), Ok(Value::Float(0.5)));
+        assert_eq!(
+            Value::Float(5.0) * Value::Float(2.5),
+            Ok(Value::Float(12.5))
+        );
+    }
+    #[test]
+    fn test_mul_str() {
+        assert_eq!(Value::from("ab") * Value::Int(3), Ok(Value::from("ababab")));
+        assert_eq!(Value::Int(2) * Value::from("ab"), Ok(Value::from("abab")));
+        assert_eq!(Value::from("ab") * Value::Int(0), Ok(Value::from("")));
+        assert_eq!(Value::from("ab") * Value::Int(-2), Ok(Value::from("")));
+        assert_eq!(
+            Value::from("a") * Value::Int(i64::MAX),
+            Err(BinOpError::Overflow)
+        );
+        assert_eq!(
+            Value::from("ab") * Value::Int(i64::try_from(MAX_STR_LEN / 2 + 1).unwrap()),
+            Err(BinOpError::Overflow)
+        );
+        let big = Value::Int(i64::MAX) + Value::Int(1);
+        assert_eq!(Value::from("a") * big.unwrap(), Err(BinOpError::Overflow));
+        let big = Value::Int(i64::MIN) - Value::Int(1);
+        assert_eq!(Value::from("a") * big.unwrap(), Ok(Value::from("")));
     }
     #[test]
     fn test_div() {
-        assert_eq!(Value::Int(7) / Value::Int(2), Value::Float(3.5));
-        assert_eq!(Value::Float(3.5) / Value::Int(2), Value::Float(1.75));
-        assert_eq!(Value::Int(4) / Value::Float(2.0), Value::Float(2.0));
-        assert_eq!(Value::Float(5.0) / Value::Float(2.5), Value::Float(2.0));
+        assert_eq!(Value::Int(7) / Value::Int(2), Ok(Value::Float(3.5)));
+        assert_eq!(Value::Float(3.5) / Value::Int(2), Ok(Value::Float(1.75)));
+        assert_eq!(Value::Int(4) / Value::Float(2.0), Ok(Value::Float(2.0)));
+        assert_eq!(Value::Float(5.0) / Value::Float(2.5), Ok(Value::Float(2.0)));
+        assert_eq!(
+            Value::Int(1) / Value::Int(0),
+            Ok(Value::Float(f64::INFINITY))
+        );
+    }
+    #[test]
+    fn test_rem() {
+        assert_eq!(Value::Int(7) % Value::Int(3), Ok(Value::Int(1)));
+        assert_eq!(Value::Int(-7) % Value::Int(3), Ok(Value::Int(2)));
+        assert_eq!(Value::Int(7) % Value::Int(-3), Ok(Value::Int(-2)));
+        assert_eq!(Value::Int(-7) % Value::Int(-3), Ok(Value::Int(-1)));
+        assert_eq!(Value::Float(-7.5) % Value::Int(2), Ok(Value::Float(0.5)));
+        assert_eq!(Value::Int(7) % Value::Int(0), Err(BinOpError::ZeroDivision));
+        assert!(matches!(Value::Int(7) % Value::Float(0.0), Ok(Value::Float(f)) if f.is_nan()));
+    }
+    #[test]
+    fn test_floor_div() {
+        assert_eq!(Value::Int(7).floor_div(Value::Int(2)), Ok(Value::Int(3)));
+        assert_eq!(Value::Int(-7).floor_div(Value::Int(2)), Ok(Value::Int(-4)));
+        assert_eq!(Value::Int(7).floor_div(Value::Int(-2)), Ok(Value::Int(-4)));
+        assert_eq!(Value::Int(-8).floor_div(Value::Int(-2)), Ok(Value::Int(4)));
+        assert_eq!(
+            Value::Float(7.5).floor_div(Value::Int(2)),
+            Ok(Value::Float(3.0))
+        );
+        assert_eq!(
+            Value::Int(1).floor_div(Value::Int(0)),
+            Err(BinOpError::ZeroDivision)
+        );
+    }
+    #[test]
+    fn test_pow() {
+        assert_eq!(Value::Int(2).pow(Value::Int(10)), Ok(Value::Int(1024)));
+        assert_eq!(Value::Int(-3).pow(Value::Int(3)), Ok(Value::Int(-27)));
+        assert_eq!(Value::Int(5).pow(Value::Int(0)), Ok(Value::Int(1)));
+        assert_eq!(Value::Int(0).pow(Value::Int(0)), Ok(Value::Int(1)));
+        assert_eq!(Value::Int(-1).pow(Value::Int(1 << 40)), Ok(Value::Int(1)));
+        assert_eq!(Value::Int(2).pow(Value::Int(-1)), Ok(Value::Float(0.5)));
+        assert_eq!(
+            Value::Float(4.0).pow(Value::Float(0.5)),
+            Ok(Value::Float(2.0))
+        );
+    }
+    #[test]
+    fn test_overflow() {
+        let (min, max) = (Value::Int(i64::MIN), Value::Int(i64::MAX));
+        let big = |s: &str| Ok(Value::BigInt(s.parse().unwrap()));
+        assert_eq!(max.clone() + Value::Int(1), big("9223372036854775808"));
+        assert_eq!(min.clone() + Value::Int(-1), big("-9223372036854775809"));
+        assert_eq!(min.clone() - Value::Int(1), big("-9223372036854775809"));
+        assert_eq!(max.clone() - Value::Int(-1), big("9223372036854775808"));
+        assert_eq!(max.clone() * Value::Int(2), big("18446744073709551614"));
+        assert_eq!(min.clone() * Value::Int(-1), big("9223372036854775808"));
+        assert_eq!(
+            Value::Int(2).pow(Value::Int(63)),
+            big("9223372036854775808")
+        );
+        assert_eq!(
+            Value::Int(2).pow(Value::Int(1 << 40)),
+            Err(BinOpError::Overflow)
+        );
+        assert_eq!(
+            Value::Int(2).pow(Value::Int(4_000_000_000)),
+            Err(BinOpError::Overflow)
+        );
+        assert_eq!(
+            Value::Int(3).pow(Value::Int(1 << 20)),
+            Err(BinOpError::Overflow)
+        );
+        let Ok(Value::BigInt(int)) = Value::Int(2).pow(Value::Int(100_000)) else {
+            panic!()
+        };
+        assert_eq!(int.bit_len(), 100_001);
+
+        let largest = Value::Int(2).pow(Value::Int(i64::try_from(MAX_LIMBS * 32 - 1).unwrap()));
+        assert!(matches!(largest, Ok(Value::BigInt(_))));
+        let largest = largest.unwrap();
+        assert_eq!(largest.clone() + largest.clone(), Err(BinOpError::Overflow));
+        assert_eq!(
+            largest.clone() - (Value::Int(0) - largest.clone()).unwrap(),
+            Err(BinOpError::Overflow)
+        );
+        assert_eq!(largest.clone() * Value::Int(2), Err(BinOpError::Overflow));
+        assert_eq!(largest.clone() * largest, Err(BinOpError::Overflow));
+        assert_eq!(
+            min.clone().floor_div(Value::Int(-1)),
+            big("9223372036854775808")
+        );
+        assert_eq!(min.clone() % Value::Int(-1), Ok(Value::Int(0)));
+
+        assert_eq!(max.clone() + Value::Int(0), Ok(max.clone()));
+        assert_eq!(min.clone() + max.clone(), Ok(Value::Int(-1)));
+        assert_eq!(min.clone() - Value::Int(0), Ok(min.clone()));
+        assert_eq!(Value::Int(-2).pow(Value::Int(63)), Ok(min.clone()));
+        assert_eq!(
+            max.clone() + Value::Float(1.0),
+            Ok(Value::Float(i64::MAX as f64))
+        );
+    }
+    #[test]
+    fn test_repeated_squaring_overflows() {
+        let mut value = Value::Int(3);
+        let mut squarings = 0;
+        let error = loop {
+            match value.clone() * value {
+                Ok(square) => value = square,
+                Err(error) => break error,
+            }
+            squarings += 1;
+        };
+        assert_eq!(error, BinOpError::Overflow);
+        // 3 ** 2 ** 17 is the first square with more than `MAX_LIMBS` limbs.
+        assert_eq!(squarings, 16);
+    }
+    #[test]
+    fn test_bigint_demotion() {
+        let max = Value::Int(i64::MAX);
+        let promoted = (max.clone() + Value::Int(1)).unwrap();
+        assert!(matches!(promoted, Value::BigInt(_)));
+        assert_eq!(promoted.clone() - Value::Int(1), Ok(max.clone()));
+        assert_eq!(promoted.clone() % Value::Int(10), Ok(Value::Int(8)));
+        assert_eq!(
+            promoted.clone().floor_div(Value::Int(1 << 32)),
+            Ok(Value::Int(1 << 31))
+        );
+        assert_eq!(
+            promoted.clone() / Value::Int(2),
+            Ok(Value::Float(2f64.powi(62)))
+        );
+        assert_eq!(
+            promoted.clone() % Value::Int(0),
+            Err(BinOpError::ZeroDivision)
+        );
+        assert_eq!(
+            promoted.clone() * promoted.clone(),
+            Ok(Value::BigInt(
+                "85070591730234615865843651857942052864".parse().unwrap()
+            ))
+        );
+        assert_eq!(Value::Int(1).pow(promoted.clone()), Ok(Value::Int(1)));
+        assert_eq!(Value::Int(-1).pow(promoted.clone()), Ok(Value::Int(1)));
+        assert_eq!(promoted.clone() & Value::Int(1), Err(BinOpError::Overflow));
+        assert!(
+            promoted.compare(&max) == Ok(Some(Ordering::Greater))
+                && (Value::Int(i64::MIN) - promoted.clone())
+                    .unwrap()
+                    .compare(&Value::Int(i64::MIN))
+                    == Ok(Some(Ordering::Less))
+        );
+    }
+    #[test]
+    fn test_compare() {
+        let cmp = |lhs: Value, rhs: Value| lhs.compare(&rhs).unwrap();
+        assert_eq!(cmp(Value::Int(1), Value::Float(2.0)), Some(Ordering::Less));
+        assert_eq!(
+            cmp(Value::Float(2.5), Value::Int(2)),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            cmp(Value::Int(-3), Value::Float(-2.5)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(cmp(Value::Int(2), Value::Float(2.0)), Some(Ordering::Equal));
+        assert_eq!(
+            cmp(
+                Value::Int(9_007_199_254_740_993),
+                Value::Float(9_007_199_254_740_992.0)
+            ),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            cmp(Value::Int(i64::MAX), Value::Float(2f64.powi(63))),
+            Some(Ordering::Less)
+        );
+        let big = (Value::Int(i64::MAX) + Value::Int(1)).unwrap();
+        assert_eq!(
+            cmp(big.clone(), Value::Float(2f64.powi(63))),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            cmp(big.clone(), Value::Float(f64::INFINITY)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(cmp(Value::Float(-1e300), big), Some(Ordering::Less));
+        assert_eq!(cmp(Value::Int(1), Value::Float(f64::NAN)), None);
+        assert_eq!(
+            cmp(Value::from("a"), Value::from("b")),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            cmp(Value::from("b"), Value::from("ab")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            Value::from("a").compare(&Value::Int(1)),
+            Err(BinOpError::Incomparable {
+                lhs: "str",
+                rhs: "int"
+            })
+        );
+    }
+    #[test]
+    fn test_equals() {
+        assert!(Value::Int(1).equals(&Value::Float(1.0)));
+        assert!(Value::from("a").equals(&Value::from("a")));
+        assert!(!Value::from("1").equals(&Value::Int(1)));
+        assert!(!Value::Float(f64::NAN).equals(&Value::Float(f64::NAN)));
+    }
+    #[test]
+    fn test_total_cmp() {
+        let mut values = [
+            Value::from("b"),
+            Value::Float(f64::NAN),
+            Value::Float(0.5),
+            Value::from("a"),
+            Value::Int(-1),
+            Value::Float(f64::NEG_INFINITY),
+        ];
+        values.sort_by(Value::total_cmp);
+        assert_eq!(values[0], Value::Float(f64::NEG_INFINITY));
+        assert_eq!(values[1], Value::Int(-1));
+        assert_eq!(values[2], Value::Float(0.5));
+        assert!(values[3].is_nan());
+        assert_eq!(values[4..], [Value::from("a"), Value::from("b")]);
+    }
+    #[test]
+    fn test_bitwise() {
+        assert_eq!(
+            Value::Int(0b1100) & Value::Int(0b1010),
+            Ok(Value::Int(0b1000))
+        );
+        assert_eq!(
+            Value::Int(0b1100) | Value::Int(0b1010),
+            Ok(Value::Int(0b1110))
+        );
+        assert_eq!(
+            Value::Int(0b1100) ^ Value::Int(0b1010),
+            Ok(Value::Int(0b0110))
+        );
+        assert_eq!(Value::Int(0).bit_not(), Ok(Value::Int(-1)));
+        assert!(Value::Float(1.0).bit_not().is_err());
+        assert!((Value::Float(1.0) & Value::Int(1)).is_err());
+    }
+    #[test]
+    fn test_shift() {
+        assert_eq!(Value::Int(1) << Value::Int(4), Ok(Value::Int(16)));
+        assert_eq!(Value::Int(1) << Value::Int(63), Ok(Value::Int(i64::MIN)));
+        assert_eq!(Value::Int(1) << Value::Int(64), Ok(Value::Int(0)));
+        assert_eq!(Value::Int(-16) >> Value::Int(2), Ok(Value::Int(-4)));
+        assert_eq!(Value::Int(-16) >> Value::Int(100), Ok(Value::Int(-1)));
+        assert_eq!(Value::Int(16) >> Value::Int(100), Ok(Value::Int(0)));
+        assert_eq!(
+            Value::Int(1) << Value::Int(-1),
+            Err(BinOpError::NegativeShift)
+        );
+        assert_eq!(
+            Value::Int(1) >> Value::Int(-1),
+            Err(BinOpError::NegativeShift)
+        );
+    }
+    #[test]
+    fn test_unsupported() {
+        assert_eq!(
+            Value::from("a") - Value::Int(1),
+            Err(BinOpError::Unsupported {
+                op: "-",
+                lhs: "str",
+                rhs: "int"
+            })
+        );
+        assert!(Value::from("a").pow(Value::Int(2)).is_err());
     }
 }
@@ -1,4 +1,6 @@
-#[derive(Clone, Copy)]
+use crate::op_codes::StackEffect;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Builtin {
     Print,
@@ -7,6 +9,18 @@ pub enum Builtin {
     Exit,
 }
 
+impl Builtin {
+    /// `Exit` pops its exit code if there is one, so it is counted as
+    /// popping nothing.
+    #[must_use]
+    pub fn stack_effect(self) -> StackEffect {
+        match self {
+            Self::Print => StackEffect { pops: 1, pushes: 0 },
+            Self::Exit => StackEffect { pops: 0, pushes: 0 },
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct InvalidBuiltin;
 impl TryFrom<u8> for Builtin {
@@ -15,6 +29,6 @@ impl TryFrom<u8> for Builtin {
         if value > Self::Exit as u8 {
             return Err(InvalidBuiltin);
         }
-        Ok(unsafe { std::mem::transmute(value) })
+        Ok(unsafe { std::mem::transmute::<u8, Self>(value) })
     }
 }
@@ -0,0 +1,280 @@
+use std::fmt::Write;
+
+use crate::{
+    instruction::{DecodeError, Instruction},
+    program::Program,
+};
+
+/// A straight-line run of instructions that is only entered at its first
+/// instruction and only left after its last one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// Index of the first instruction in [`Cfg::instructions`].
+    pub start: usize,
+    /// Index one past the last instruction.
+    pub end: usize,
+    pub predecessors: Vec<usize>,
+    pub successors: Vec<usize>,
+}
+
+/// The control-flow graph of a program.
+///
+/// A function call (`PrepareFuncCall; Jump`) has edges both to the function
+/// and to its return site. `Ret` has no successors, `TailCall` only has an
+/// edge to the function, and jumps to the end of the program have no edge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    /// Every instruction in the program with its offset.
+    pub instructions: Vec<(usize, Instruction)>,
+    pub blocks: Vec<Block>,
+}
+
+impl Cfg {
+    /// # Errors
+    /// Fails if the bytecode does not decode or a jump does not land on an
+    /// instruction boundary.
+    pub fn new(program: &Program) -> Result<Self, DecodeError> {
+        let instructions = program.instructions().collect::<Result<Vec<_>, _>>()?;
+        let index_of = |offset: usize| {
+            instructions
+                .binary_search_by_key(&offset, |&(offset, _)| offset)
+                .or_else(|i| {
+                    if offset == program.len() {
+                        Ok(i)
+                    } else {
+                        Err(i)
+                    }
+                })
+        };
+
+        let mut leaders = vec![false; instructions.len() + 1];
+        leaders[0] = true;
+        for (i, &(offset, inst)) in instructions.iter().enumerate() {
+            if let Some(target) = inst.jump_target() {
+                let target = target as usize;
+                let index = index_of(target)
+                    .map_err(|_| DecodeError::InvalidJumpTarget { offset, target })?;
+                leaders[index] = true;
+            }
+            if inst.jump_target().is_some() || inst == Instruction::Ret {
+                leaders[i + 1] = true;
+            }
+        }
+
+        let starts: Vec<usize> = (0..instructions.len()).filter(|&i| leaders[i]).collect();
+        let mut blocks: Vec<Block> = starts
+            .iter()
+            .enumerate()
+            .map(|(b, &start)| Block {
+                start,
+                end: starts.get(b + 1).copied().unwrap_or(instructions.len()),
+                predecessors: vec![],
+                successors: vec![],
+            })
+            .collect();
+
+        let block_of = |index: usize| starts.binary_search(&index).ok();
+        for b in 0..blocks.len() {
+            let last = blocks[b].end - 1;
+            let (_, inst) = instructions[last];
+            let is_call = last > 0 && instructions[last - 1].1 == Instruction::PrepareFuncCall;
+            let falls_through = match inst {
+                Instruction::Jump(_) => is_call,
+                Instruction::Ret | Instruction::TailCall(_) => false,
+                _ => true,
+            };
+            let mut successors = vec![];
+            if falls_through {
+                successors.extend(block_of(last + 1));
+            }
+            if let Some(target) = inst.jump_target() {
+                let target = index_of(target as usize).unwrap();
+                successors.extend(block_of(target));
+            }
+            successors.dedup();
+            for &succ in &successors {
+                blocks[succ].predecessors.push(b);
+            }
+            blocks[b].successors = successors;
+        }
+        Ok(Self {
+            instructions,
+            blocks,
+        })
+    }
+
+    #[must_use]
+    pub fn block_instructions(&self, block: &Block) -> &[(usize, Instruction)] {
+        &self.instructions[block.start..block.end]
+    }
+
+    /// The block containing the instruction at byte `offset`.
+    #[must_use]
+    pub fn block_at(&self, offset: usize) -> Option<usize> {
+        let index = self
+            .instructions
+            .binary_search_by_key(&offset, |&(offset, _)| offset)
+            .ok()?;
+        Some(self.blocks.partition_point(|block| block.start <= index) - 1)
+    }
+
+    /// Renders the graph in Graphviz DOT format, with each block labelled
+    /// by its disassembly.
+    #[must_use]
+    pub fn to_dot(&self, program: &Program) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box fontname=monospace];\n");
+        for (b, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for &(offset, _) in self.block_instructions(block) {
+                program.write_instruction(&mut label, offset).unwrap();
+                label.push('\n');
+            }
+            let label = label
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\l");
+            writeln!(dot, "    b{b} [label=\"{label}\"];").unwrap();
+        }
+        for (b, block) in self.blocks.iter().enumerate() {
+            let (_, last) = self.instructions[block.end - 1];
+            for &succ in &block.successors {
+                let branch = match last {
+                    Instruction::PopJumpIfFalse(_)
+                    | Instruction::JumpIfNotLtConst(..)
+                    | Instruction::JumpIfFalseOrPop(_) => " [label=\"false\"]",
+                    Instruction::PopJumpIfTrue(_) | Instruction::JumpIfTrueOrPop(_) => {
+                        " [label=\"true\"]"
+                    }
+                    _ => "",
+                };
+                let attrs = if self.blocks[succ].start == block.end {
+                    ""
+                } else {
+                    branch
+                };
+                writeln!(dot, "    b{b} -> b{succ}{attrs};").unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::compile_str, op_codes::OpCode};
+
+    fn edges(cfg: &Cfg) -> Vec<(usize, Vec<usize>, Vec<usize>)> {
+        cfg.blocks
+            .iter()
+            .map(|block| {
+                (
+                    cfg.instructions[block.start].0,
+                    block.predecessors.clone(),
+                    block.successors.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_while_loop() {
+        let program = compile_str(include_str!("../examples/while_loop.pty"));
+        let cfg = Cfg::new(&program).unwrap();
+
+        assert_eq!(
+            edges(&cfg),
+            [
+                (0, vec![], vec![1]),
+                (10, vec![0, 2], vec![2]),
+                (22, vec![1], vec![1])
+            ]
+        );
+        assert_eq!(cfg.block_at(16), Some(1));
+        assert_eq!(cfg.block_at(17), Some(1));
+        assert_eq!(cfg.block_at(18), None);
+    }
+
+    #[test]
+    fn test_if_else() {
+        let mut program = Program::new();
+        program.push_literal(1);
+        program.push_if_or_else(
+            |p| {
+                p.push_literal(2);
+            },
+            |p| {
+                p.push_literal(3);
+            },
+        );
+        program.push_opcode(OpCode::Pop);
+        let cfg = Cfg::new(&program).unwrap();
+
+        assert_eq!(
+            edges(&cfg),
+            [
+                (0, vec![], vec![1, 2]),
+                (10, vec![0], vec![3]),
+                (20, vec![0], vec![3]),
+                (25, vec![1, 2], vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_function_call() {
+        let mut program = Program::new();
+        let func = program.push_func(|p| p.push_opcode(OpCode::Dup));
+        program.push_literal(1);
+        program.call_func(func);
+        program.push_opcode(OpCode::Pop);
+        let cfg = Cfg::new(&program).unwrap();
+
+        assert_eq!(
+            edges(&cfg),
+            [
+                (0, vec![], vec![2]),
+                (5, vec![2], vec![]),
+                (7, vec![0], vec![3, 1]),
+                (18, vec![2], vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut program = Program::new();
+        program.push_jump(3);
+        program.push_literal(1);
+        assert_eq!(
+            Cfg::new(&program),
+            Err(DecodeError::InvalidJumpTarget {
+                offset: 0,
+                target: 3
+            })
+        );
+        program.bytes.pop();
+        assert_eq!(
+            Cfg::new(&program),
+            Err(DecodeError::Truncated { offset: 5 })
+        );
+    }
+
+    #[test]
+    fn test_dot() {
+        let program = compile_str("1 ?end \"a\" pop @end");
+        let dot = Cfg::new(&program).unwrap().to_dot(&program);
+        assert_eq!(
+            dot,
+            concat!(
+                "digraph cfg {\n",
+                "    node [shape=box fontname=monospace];\n",
+                "    b0 [label=\"0 LoadConst 0 1\\l5 PopJumpIfFalse 16\\l\"];\n",
+                "    b1 [label=\"10 LoadConst 1 'a'\\l15 Pop\\l\"];\n",
+                "    b0 -> b1;\n",
+                "}\n",
+            )
+        );
+    }
+}
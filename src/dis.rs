@@ -1,41 +1,77 @@
 use std::fmt;
 
-use crate::{op_codes::OpCode, program::Program};
+use crate::{instruction::Instruction, program::Program};
 
+/// Lists one instruction per line. With a line table, an instruction whose
+/// source location differs from the one before it is followed by that
+/// location.
 impl fmt::Display for Program {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut head = 0;
+        let mut last = None;
         while head < self.len() {
-            write!(f, "{head}")?;
-
-            let op_code = OpCode::try_from(self[head]).unwrap();
-            head += 1;
-
-            write!(f, " {op_code:?}")?;
-            match op_code {
-                OpCode::LoadConst => {
-                    let index_bytes = self.read_arr(head).unwrap();
-                    let index = u32::from_le_bytes(index_bytes) as usize;
-                    let constant = self.constants[index].clone();
-                    write!(f, " {index} {constant}")?;
-                }
-                OpCode::Jump | OpCode::PopJumpIfFalse => {
-                    let index_bytes = self.read_arr(head).unwrap();
-                    let index = u32::from_le_bytes(index_bytes) as usize;
-                    write!(f, " {index}")?;
-                }
-                _ => (),
+            let location = self.location(head);
+            let next = self.write_instruction(f, head)?;
+            if let Some(location) = location.filter(|_| location != last) {
+                write!(f, "  // {location}")?;
             }
             writeln!(f)?;
-            head += op_code.size_operand();
+            (head, last) = (next, location);
         }
         Ok(())
     }
 }
 
+impl Program {
+    /// Writes the instruction at `head` without a trailing newline and
+    /// returns the offset of the next instruction.
+    pub(crate) fn write_instruction(
+        &self,
+        f: &mut impl fmt::Write,
+        head: usize,
+    ) -> Result<usize, fmt::Error> {
+        let inst = Instruction::decode(&self.bytes, head).unwrap();
+        write!(f, "{head} {:?}", inst.op_code())?;
+        match inst {
+            Instruction::LoadConst(index) | Instruction::AddConst(index) => {
+                let constant = &self.constants[index as usize];
+                write!(f, " {index} {constant}")?;
+            }
+            Instruction::Jump(target)
+            | Instruction::PopJumpIfFalse(target)
+            | Instruction::TailCall(target)
+            | Instruction::PopJumpIfTrue(target)
+            | Instruction::JumpIfFalseOrPop(target)
+            | Instruction::JumpIfTrueOrPop(target) => {
+                write!(f, " {target}")?;
+            }
+            Instruction::JumpIfNotLtConst(index, target) => {
+                let constant = &self.constants[index as usize];
+                write!(f, " {index} {constant} {target}")?;
+            }
+            Instruction::IncName(name, index) => {
+                let constant = &self.constants[index as usize];
+                write!(
+                    f,
+                    " {name} {} {index} {constant}",
+                    self.idents[name as usize]
+                )?;
+            }
+            Instruction::Pick(n) | Instruction::Roll(n) | Instruction::PopN(n) => {
+                write!(f, " {n}")?;
+            }
+            Instruction::LoadBuiltin(builtin) => write!(f, " {builtin:?}")?,
+            _ => (),
+        }
+        Ok(head + inst.size())
+    }
+}
+
 #[cfg(test)]
 #[test]
 fn test_dis() {
+    use crate::op_codes::OpCode;
+
     let mut program = Program::new();
 
     program.push_opcode(OpCode::Nop);
@@ -57,3 +93,18 @@ fn test_dis() {
         )
     );
 }
+
+#[cfg(test)]
+#[test]
+fn test_dis_lines() {
+    let program = crate::assembler::compile_str("1 2\n// comment\n  + dup\n@end");
+    assert_eq!(
+        program.to_string(),
+        concat!(
+            "0 LoadConst 0 1  // 1:1\n",
+            "5 LoadConst 1 2  // 1:3\n",
+            "10 Add  // 3:3\n",
+            "11 Dup  // 3:5\n",
+        )
+    );
+}
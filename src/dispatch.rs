@@ -0,0 +1,343 @@
+use std::cmp::Ordering;
+
+use crate::{
+    binops::{binary_op, unary_op, BinOpResult},
+    builtins::Builtin,
+    instruction::Instruction,
+    program::Program,
+    traceback::{self, Frame},
+    value::Value,
+    verify::{verify, VerifyError},
+    vm::{run_builtin, VmError, NO_CALLER},
+};
+
+/// An instruction with its operands resolved: jumps hold the index of the
+/// target in `ops` and operators hold the function to call.
+#[derive(Clone, Copy)]
+enum Op {
+    Nop,
+    Dup,
+    Pop,
+    Swap,
+    DupSwap,
+    Pick(u32),
+    Roll(u32),
+    PopN(u32),
+    Jump(u32),
+    /// Jumps to a function, which now returns to the caller of the current
+    /// one.
+    TailCall(u32),
+    Ret,
+    /// Pushes the index after the `Jump` that follows it, along with that
+    /// jump's target.
+    PrepareFuncCall(u32),
+    Binary(fn(Value, Value) -> BinOpResult),
+    Unary(fn(Value) -> BinOpResult),
+    LoadConst(u32),
+    StoreName(u32),
+    LoadName(u32),
+    LoadBuiltin(Builtin),
+    /// Pops the top value and jumps if its truthiness is the flag.
+    PopJumpIf(bool, u32),
+    /// Jumps if the truthiness of the top value is the flag, leaving it on
+    /// the stack, and pops it otherwise.
+    JumpIfOrPop(bool, u32),
+    AddConst(u32),
+    JumpIfNotLtConst(u32, u32),
+    IncName(u32, u32),
+}
+
+/// A verified program decoded once into an array of instructions, so running
+/// it does not decode bytes or look up operators on every step.
+///
+/// The bytecode itself is unchanged; this is only an execution mode.
+pub struct Decoded<'a> {
+    program: &'a Program,
+    ops: Vec<Op>,
+    /// The offset of the instruction each op was decoded from.
+    offsets: Vec<usize>,
+    max_depth: usize,
+}
+
+impl<'a> Decoded<'a> {
+    /// # Errors
+    /// Fails if the program does not verify.
+    pub fn new(program: &'a Program) -> Result<Self, VerifyError> {
+        let info = verify(program)?;
+        let instructions: Vec<_> = program.instructions().map(Result::unwrap).collect();
+
+        // Jumps may target the end of the program as well as any instruction.
+        let mut indices = vec![0; program.len() + 1];
+        for (index, &(offset, _)) in instructions.iter().enumerate() {
+            indices[offset] = u32::try_from(index).unwrap();
+        }
+        indices[program.len()] = u32::try_from(instructions.len()).unwrap();
+        let index_of = |target: u32| indices[target as usize];
+
+        let offsets = instructions.iter().map(|&(offset, _)| offset).collect();
+        let ops = instructions
+            .iter()
+            .enumerate()
+            .map(|(i, &(_, inst))| match inst {
+                Instruction::Nop => Op::Nop,
+                Instruction::Dup => Op::Dup,
+                Instruction::Pop => Op::Pop,
+                Instruction::Swap => Op::Swap,
+                Instruction::DupSwap => Op::DupSwap,
+                Instruction::Rot => Op::Roll(2),
+                Instruction::Over => Op::Pick(1),
+                Instruction::Pick(n) => Op::Pick(n),
+                Instruction::Roll(n) => Op::Roll(n),
+                Instruction::PopN(n) => Op::PopN(n),
+                Instruction::Jump(target) => Op::Jump(index_of(target)),
+                Instruction::TailCall(target) => Op::TailCall(index_of(target)),
+                Instruction::Ret => Op::Ret,
+                // Verification guarantees a `Jump` follows.
+                Instruction::PrepareFuncCall => {
+                    Op::PrepareFuncCall(index_of(instructions[i + 1].1.jump_target().unwrap()))
+                }
+                Instruction::LoadConst(index) => Op::LoadConst(index),
+                Instruction::StoreName(index) => Op::StoreName(index),
+                Instruction::LoadName(index) => Op::LoadName(index),
+                Instruction::LoadBuiltin(builtin) => Op::LoadBuiltin(builtin),
+                Instruction::PopJumpIfFalse(target) => Op::PopJumpIf(false, index_of(target)),
+                Instruction::PopJumpIfTrue(target) => Op::PopJumpIf(true, index_of(target)),
+                Instruction::JumpIfFalseOrPop(target) => Op::JumpIfOrPop(false, index_of(target)),
+                Instruction::JumpIfTrueOrPop(target) => Op::JumpIfOrPop(true, index_of(target)),
+                Instruction::AddConst(index) => Op::AddConst(index),
+                Instruction::JumpIfNotLtConst(index, target) => {
+                    Op::JumpIfNotLtConst(index, index_of(target))
+                }
+                Instruction::IncName(name, index) => Op::IncName(name, index),
+                _ => {
+                    let op = inst.op_code();
+                    binary_op(op)
+                        .map(Op::Binary)
+                        .or_else(|| unary_op(op).map(Op::Unary))
+                        .unwrap()
+                }
+            })
+            .collect();
+        Ok(Self {
+            program,
+            ops,
+            offsets,
+            max_depth: info.max_depth().unwrap_or_default(),
+        })
+    }
+
+    /// Runs the program from the start, returning the remaining stack.
+    ///
+    /// # Errors
+    /// Fails if the program raises a runtime error.
+    pub fn run(&self) -> Result<Vec<Value>, VmError> {
+        let constants = &self.program.constants;
+        let mut stack: Vec<Value> = Vec::with_capacity(self.max_depth);
+        let mut variables: Vec<Option<Value>> = vec![None; self.program.idents.len()];
+        let mut call_stack = vec![];
+        let mut pc = 0;
+
+        // Verification guarantees the stack never underflows and every
+        // operand is in range. The loop runs in a closure so `pc` still
+        // points past the failing op when it returns an error.
+        let result = (|| {
+            while let Some(&op) = self.ops.get(pc) {
+                pc += 1;
+                match op {
+                    Op::Nop => (),
+                    Op::Dup => {
+                        let top = stack.last().unwrap().clone();
+                        stack.push(top);
+                    }
+                    Op::Pop => _ = stack.pop(),
+                    Op::Swap => {
+                        let len = stack.len();
+                        stack.swap(len - 1, len - 2);
+                    }
+                    Op::DupSwap => {
+                        let top = stack.last().unwrap().clone();
+                        stack.push(top);
+                        let len = stack.len();
+                        stack.swap(len - 2, len - 3);
+                    }
+                    Op::Pick(n) => {
+                        let value = stack[stack.len() - 1 - n as usize].clone();
+                        stack.push(value);
+                    }
+                    Op::Roll(n) => {
+                        let len = stack.len();
+                        stack[len - 1 - n as usize..].rotate_left(1);
+                    }
+                    Op::PopN(n) => stack.truncate(stack.len() - n as usize),
+                    Op::Jump(target) => pc = target as usize,
+                    // The caller's return address is already on the call stack.
+                    Op::TailCall(target) => {
+                        if let Some((entry, _)) = call_stack.last_mut() {
+                            *entry = target as usize;
+                        }
+                        pc = target as usize;
+                    }
+                    Op::Ret => pc = call_stack.pop().expect(NO_CALLER).1,
+                    Op::PrepareFuncCall(entry) => call_stack.push((entry as usize, pc + 1)),
+                    Op::Binary(func) => {
+                        let rhs = stack.pop().unwrap();
+                        let lhs = stack.pop().unwrap();
+                        stack.push(func(lhs, rhs)?);
+                    }
+                    Op::Unary(func) => {
+                        let operand = stack.pop().unwrap();
+                        stack.push(func(operand)?);
+                    }
+                    Op::LoadConst(index) => stack.push(constants[index as usize].clone()),
+                    Op::StoreName(index) => variables[index as usize] = stack.pop(),
+                    Op::LoadName(index) => {
+                        let Some(val) = &variables[index as usize] else {
+                            let name = self.program.idents[index as usize].clone();
+                            return Err(VmError::UndefinedVariable(name));
+                        };
+                        stack.push(val.clone());
+                    }
+                    Op::LoadBuiltin(builtin) => run_builtin(builtin, &mut stack),
+                    Op::PopJumpIf(when, target) => {
+                        if bool::from(&stack.pop().unwrap()) == when {
+                            pc = target as usize;
+                        }
+                    }
+                    Op::JumpIfOrPop(when, target) => {
+                        if bool::from(stack.last().unwrap()) == when {
+                            pc = target as usize;
+                        } else {
+                            stack.pop();
+                        }
+                    }
+                    Op::AddConst(index) => {
+                        let top = stack.pop().unwrap();
+                        stack.push((top + constants[index as usize].clone())?);
+                    }
+                    Op::JumpIfNotLtConst(index, target) => {
+                        let top = stack.last().unwrap();
+                        if !top
+                            .compare(&constants[index as usize])?
+                            .is_some_and(Ordering::is_lt)
+                        {
+                            pc = target as usize;
+                        }
+                    }
+                    Op::IncName(name, index) => {
+                        let Some(val) = variables[name as usize].as_mut() else {
+                            let name = self.program.idents[name as usize].clone();
+                            return Err(VmError::UndefinedVariable(name));
+                        };
+                        val.add_in_place(constants[index as usize].clone())?;
+                    }
+                }
+            }
+            Ok(())
+        })();
+        result.map_err(|err| err.at(self.traceback(&call_stack, pc - 1)))?;
+        Ok(stack)
+    }
+
+    /// The frames active when the op at `failed` raised an error, given the
+    /// entry and return index of each call.
+    fn traceback(&self, call_stack: &[(usize, usize)], failed: usize) -> Vec<Frame> {
+        // Each call returns to the op after its 5-byte `Jump`.
+        let calls = call_stack
+            .iter()
+            .map(|&(entry, ret)| (self.offsets[entry], self.offsets[ret - 1] + 5));
+        traceback::capture(self.program, calls, self.offsets[failed])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::compile_str, op_codes::OpCode, optimize::fuse_instructions, vm::Vm};
+
+    /// Runs `program` with both the byte-stepping `Vm` and `Decoded`.
+    fn run_both(program: &Program) -> Result<Vec<Value>, VmError> {
+        let mut vm = Vm::from(program);
+        let stepped = vm.run().map(|()| vm.stack().to_vec());
+        let decoded = Decoded::new(program)?.run();
+        assert_eq!(stepped, decoded);
+        decoded
+    }
+
+    #[test]
+    fn test_same_as_vm() {
+        let program = compile_str(include_str!("../examples/while_loop.pty"));
+        assert_eq!(
+            run_both(&program),
+            Ok(vec![Value::Int(5040), Value::Int(7)])
+        );
+
+        let program = compile_str("\"a\" \"b\" + 2 * 7 2 ~/ 1 0 ~/ 3");
+        assert!(run_both(&program).is_err());
+
+        let mut program = Program::new();
+        program.push_literal(3);
+        program.store_name("n");
+        let entry = program.len() + 5;
+        let fib = program.push_func(|func| {
+            func.push_opcode(OpCode::Dup);
+            func.push_literal(2);
+            func.push_opcode(OpCode::Lt);
+            func.push_if_or_else(
+                |_| {},
+                |orelse| {
+                    orelse.push_opcode(OpCode::Dup);
+                    orelse.push_literal(1);
+                    orelse.push_opcode(OpCode::Sub);
+                    orelse.call_func(entry);
+                    orelse.push_opcode(OpCode::Swap);
+                    orelse.push_literal(2);
+                    orelse.push_opcode(OpCode::Sub);
+                    orelse.call_func(entry);
+                    orelse.push_opcode(OpCode::Add);
+                },
+            );
+        });
+        program.load_name("n");
+        program.push_literal(7);
+        program.push_opcode(OpCode::Mul);
+        program.call_func(fib);
+        assert_eq!(run_both(&program), Ok(vec![Value::Int(10946)]));
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let program = fuse_instructions(&compile_str(include_str!("../examples/while_loop.pty")));
+        assert_eq!(
+            run_both(&program),
+            Ok(vec![Value::Int(5040), Value::Int(7)])
+        );
+
+        // The fused comparison raises the same error as `Lt`.
+        let program = fuse_instructions(&compile_str("\"a\" @start dup 1 < ?end $start @end"));
+        assert!(program.to_string().contains("JumpIfNotLtConst"));
+        assert!(run_both(&program).is_err());
+
+        let program = fuse_instructions(&compile_str(
+            "0.0 0.0 / @start dup 1 < ?end $start @end pop 5",
+        ));
+        assert_eq!(run_both(&program), Ok(vec![Value::Int(5)]));
+
+        let mut program = Program::new();
+        program.load_name("x");
+        program.push_literal(1);
+        program.push_opcode(OpCode::Add);
+        program.store_name("x");
+        let program = fuse_instructions(&program);
+        assert!(program.to_string().contains("IncName"));
+        assert_eq!(
+            run_both(&program).unwrap_err().kind(),
+            &VmError::UndefinedVariable("x".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_jump_to_end() {
+        let program = compile_str("1 $end 2 @end");
+        assert_eq!(run_both(&program), Ok(vec![Value::Int(1)]));
+    }
+}
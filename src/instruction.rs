@@ -0,0 +1,453 @@
+use std::fmt;
+
+use crate::{
+    builtins::Builtin,
+    op_codes::{OpCode, StackEffect},
+    program::Program,
+};
+
+/// A decoded instruction. Jump operands are byte offsets into the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    Dup,
+    Pop,
+    Swap,
+    DupSwap,
+
+    Jump(u32),
+
+    Ret,
+    PrepareFuncCall,
+
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    IntDiv,
+
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+
+    Le,
+    Lt,
+    Ge,
+    Gt,
+    Eq,
+    Ne,
+
+    UnaryNot,
+
+    LoadConst(u32),
+    StoreName(u32),
+    LoadName(u32),
+
+    LoadBuiltin(Builtin),
+
+    PopJumpIfFalse(u32),
+
+    /// Adds the constant to the value on top of the stack.
+    AddConst(u32),
+    /// Jumps to the second operand unless the value on top of the stack is
+    /// less than the constant. The value is left on the stack.
+    JumpIfNotLtConst(u32, u32),
+    /// Adds the constant in the second operand to the variable in the first.
+    IncName(u32, u32),
+
+    /// Jumps to the function at the operand, which returns to the caller of
+    /// the current one.
+    TailCall(u32),
+
+    Rot,
+    Over,
+    Pick(u32),
+    Roll(u32),
+    PopN(u32),
+
+    PopJumpIfTrue(u32),
+    JumpIfFalseOrPop(u32),
+    JumpIfTrueOrPop(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidOpCode { offset: usize, byte: u8 },
+    InvalidBuiltin { offset: usize, byte: u8 },
+    Truncated { offset: usize },
+    InvalidJumpTarget { offset: usize, target: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOpCode { offset, byte } => {
+                write!(f, "invalid opcode {byte} at offset {offset}")
+            }
+            Self::InvalidBuiltin { offset, byte } => {
+                write!(f, "invalid builtin {byte} at offset {offset}")
+            }
+            Self::Truncated { offset } => {
+                write!(f, "truncated instruction at offset {offset}")
+            }
+            Self::InvalidJumpTarget { offset, target } => {
+                write!(
+                    f,
+                    "jump at offset {offset} to {target} is not an instruction"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Instruction {
+    /// Decodes the instruction starting at `offset`.
+    ///
+    /// # Errors
+    /// Fails on an unknown opcode or builtin, or an operand that runs past
+    /// the end of `bytes`.
+    pub fn decode(bytes: &[u8], offset: usize) -> Result<Self, DecodeError> {
+        let byte = *bytes.get(offset).ok_or(DecodeError::Truncated { offset })?;
+        let op = OpCode::try_from(byte).map_err(|_| DecodeError::InvalidOpCode { offset, byte })?;
+        let operand = bytes
+            .get(offset + 1..offset + 1 + op.size_operand())
+            .ok_or(DecodeError::Truncated { offset })?;
+        let u32_operand = |at: usize| u32::from_le_bytes(operand[at..at + 4].try_into().unwrap());
+
+        Ok(match op {
+            OpCode::Nop => Self::Nop,
+            OpCode::Dup => Self::Dup,
+            OpCode::Pop => Self::Pop,
+            OpCode::Swap => Self::Swap,
+            OpCode::DupSwap => Self::DupSwap,
+            OpCode::Jump => Self::Jump(u32_operand(0)),
+            OpCode::Ret => Self::Ret,
+            OpCode::PrepareFuncCall => Self::PrepareFuncCall,
+            OpCode::Add => Self::Add,
+            OpCode::Sub => Self::Sub,
+            OpCode::Mul => Self::Mul,
+            OpCode::Div => Self::Div,
+            OpCode::Mod => Self::Mod,
+            OpCode::Pow => Self::Pow,
+            OpCode::IntDiv => Self::IntDiv,
+            OpCode::BitAnd => Self::BitAnd,
+            OpCode::BitOr => Self::BitOr,
+            OpCode::BitXor => Self::BitXor,
+            OpCode::BitNot => Self::BitNot,
+            OpCode::Shl => Self::Shl,
+            OpCode::Shr => Self::Shr,
+            OpCode::Le => Self::Le,
+            OpCode::Lt => Self::Lt,
+            OpCode::Ge => Self::Ge,
+            OpCode::Gt => Self::Gt,
+            OpCode::Eq => Self::Eq,
+            OpCode::Ne => Self::Ne,
+            OpCode::UnaryNot => Self::UnaryNot,
+            OpCode::LoadConst => Self::LoadConst(u32_operand(0)),
+            OpCode::StoreName => Self::StoreName(u32_operand(0)),
+            OpCode::LoadName => Self::LoadName(u32_operand(0)),
+            OpCode::LoadBuiltin => {
+                let byte = operand[0];
+                let builtin = Builtin::try_from(byte)
+                    .map_err(|_| DecodeError::InvalidBuiltin { offset, byte })?;
+                Self::LoadBuiltin(builtin)
+            }
+            OpCode::PopJumpIfFalse => Self::PopJumpIfFalse(u32_operand(0)),
+            OpCode::AddConst => Self::AddConst(u32_operand(0)),
+            OpCode::JumpIfNotLtConst => Self::JumpIfNotLtConst(u32_operand(0), u32_operand(4)),
+            OpCode::IncName => Self::IncName(u32_operand(0), u32_operand(4)),
+            OpCode::TailCall => Self::TailCall(u32_operand(0)),
+            OpCode::Rot => Self::Rot,
+            OpCode::Over => Self::Over,
+            OpCode::Pick => Self::Pick(u32_operand(0)),
+            OpCode::Roll => Self::Roll(u32_operand(0)),
+            OpCode::PopN => Self::PopN(u32_operand(0)),
+            OpCode::PopJumpIfTrue => Self::PopJumpIfTrue(u32_operand(0)),
+            OpCode::JumpIfFalseOrPop => Self::JumpIfFalseOrPop(u32_operand(0)),
+            OpCode::JumpIfTrueOrPop => Self::JumpIfTrueOrPop(u32_operand(0)),
+            OpCode::StopCode => unreachable!("StopCode"),
+        })
+    }
+
+    /// Appends the encoded instruction to `out`.
+    pub fn encode(self, out: &mut Vec<u8>) {
+        out.push(self.op_code() as u8);
+        match self {
+            Self::Jump(operand)
+            | Self::LoadConst(operand)
+            | Self::StoreName(operand)
+            | Self::LoadName(operand)
+            | Self::PopJumpIfFalse(operand)
+            | Self::AddConst(operand)
+            | Self::TailCall(operand)
+            | Self::Pick(operand)
+            | Self::Roll(operand)
+            | Self::PopN(operand)
+            | Self::PopJumpIfTrue(operand)
+            | Self::JumpIfFalseOrPop(operand)
+            | Self::JumpIfTrueOrPop(operand) => out.extend_from_slice(&operand.to_le_bytes()),
+            Self::JumpIfNotLtConst(first, second) | Self::IncName(first, second) => {
+                out.extend_from_slice(&first.to_le_bytes());
+                out.extend_from_slice(&second.to_le_bytes());
+            }
+            Self::LoadBuiltin(builtin) => out.push(builtin as u8),
+            _ => (),
+        }
+    }
+
+    #[must_use]
+    pub fn op_code(self) -> OpCode {
+        match self {
+            Self::Nop => OpCode::Nop,
+            Self::Dup => OpCode::Dup,
+            Self::Pop => OpCode::Pop,
+            Self::Swap => OpCode::Swap,
+            Self::DupSwap => OpCode::DupSwap,
+            Self::Jump(_) => OpCode::Jump,
+            Self::Ret => OpCode::Ret,
+            Self::PrepareFuncCall => OpCode::PrepareFuncCall,
+            Self::Add => OpCode::Add,
+            Self::Sub => OpCode::Sub,
+            Self::Mul => OpCode::Mul,
+            Self::Div => OpCode::Div,
+            Self::Mod => OpCode::Mod,
+            Self::Pow => OpCode::Pow,
+            Self::IntDiv => OpCode::IntDiv,
+            Self::BitAnd => OpCode::BitAnd,
+            Self::BitOr => OpCode::BitOr,
+            Self::BitXor => OpCode::BitXor,
+            Self::BitNot => OpCode::BitNot,
+            Self::Shl => OpCode::Shl,
+            Self::Shr => OpCode::Shr,
+            Self::Le => OpCode::Le,
+            Self::Lt => OpCode::Lt,
+            Self::Ge => OpCode::Ge,
+            Self::Gt => OpCode::Gt,
+            Self::Eq => OpCode::Eq,
+            Self::Ne => OpCode::Ne,
+            Self::UnaryNot => OpCode::UnaryNot,
+            Self::LoadConst(_) => OpCode::LoadConst,
+            Self::StoreName(_) => OpCode::StoreName,
+            Self::LoadName(_) => OpCode::LoadName,
+            Self::LoadBuiltin(_) => OpCode::LoadBuiltin,
+            Self::PopJumpIfFalse(_) => OpCode::PopJumpIfFalse,
+            Self::AddConst(_) => OpCode::AddConst,
+            Self::JumpIfNotLtConst(..) => OpCode::JumpIfNotLtConst,
+            Self::IncName(..) => OpCode::IncName,
+            Self::TailCall(_) => OpCode::TailCall,
+            Self::Rot => OpCode::Rot,
+            Self::Over => OpCode::Over,
+            Self::Pick(_) => OpCode::Pick,
+            Self::Roll(_) => OpCode::Roll,
+            Self::PopN(_) => OpCode::PopN,
+            Self::PopJumpIfTrue(_) => OpCode::PopJumpIfTrue,
+            Self::JumpIfFalseOrPop(_) => OpCode::JumpIfFalseOrPop,
+            Self::JumpIfTrueOrPop(_) => OpCode::JumpIfTrueOrPop,
+        }
+    }
+
+    /// The encoded size in bytes, including the opcode.
+    #[must_use]
+    pub fn size(self) -> usize {
+        1 + self.op_code().size_operand()
+    }
+
+    #[must_use]
+    pub fn jump_target(self) -> Option<u32> {
+        match self {
+            Self::Jump(target)
+            | Self::PopJumpIfFalse(target)
+            | Self::JumpIfNotLtConst(_, target)
+            | Self::TailCall(target)
+            | Self::PopJumpIfTrue(target)
+            | Self::JumpIfFalseOrPop(target)
+            | Self::JumpIfTrueOrPop(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    /// Replaces the target of a jump. Other instructions are returned
+    /// unchanged.
+    #[must_use]
+    pub fn with_jump_target(self, target: u32) -> Self {
+        match self {
+            Self::Jump(_) => Self::Jump(target),
+            Self::PopJumpIfFalse(_) => Self::PopJumpIfFalse(target),
+            Self::JumpIfNotLtConst(index, _) => Self::JumpIfNotLtConst(index, target),
+            Self::TailCall(_) => Self::TailCall(target),
+            Self::PopJumpIfTrue(_) => Self::PopJumpIfTrue(target),
+            Self::JumpIfFalseOrPop(_) => Self::JumpIfFalseOrPop(target),
+            Self::JumpIfTrueOrPop(_) => Self::JumpIfTrueOrPop(target),
+            _ => self,
+        }
+    }
+
+    /// The index of the constant the instruction reads, if any.
+    #[must_use]
+    pub fn constant(self) -> Option<u32> {
+        match self {
+            Self::LoadConst(index)
+            | Self::AddConst(index)
+            | Self::JumpIfNotLtConst(index, _)
+            | Self::IncName(_, index) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// Replaces the constant index. Other instructions are returned
+    /// unchanged.
+    #[must_use]
+    pub fn with_constant(self, index: u32) -> Self {
+        match self {
+            Self::LoadConst(_) => Self::LoadConst(index),
+            Self::AddConst(_) => Self::AddConst(index),
+            Self::JumpIfNotLtConst(_, target) => Self::JumpIfNotLtConst(index, target),
+            Self::IncName(name, _) => Self::IncName(name, index),
+            _ => self,
+        }
+    }
+
+    #[must_use]
+    pub fn stack_effect(self) -> StackEffect {
+        match self {
+            Self::LoadBuiltin(builtin) => builtin.stack_effect(),
+            Self::Pick(n) => StackEffect {
+                pops: n as usize + 1,
+                pushes: n as usize + 2,
+            },
+            Self::Roll(n) => StackEffect {
+                pops: n as usize + 1,
+                pushes: n as usize + 1,
+            },
+            Self::PopN(n) => StackEffect {
+                pops: n as usize,
+                pushes: 0,
+            },
+            _ => self.op_code().stack_effect().unwrap(),
+        }
+    }
+}
+
+/// Iterator over the instructions of a [`Program`], created by
+/// [`Program::instructions`].
+pub struct Instructions<'a> {
+    bytes: &'a [u8],
+    head: usize,
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = Result<(usize, Instruction), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.head >= self.bytes.len() {
+            return None;
+        }
+        let offset = self.head;
+        match Instruction::decode(self.bytes, offset) {
+            Ok(inst) => {
+                self.head += inst.size();
+                Some(Ok((offset, inst)))
+            }
+            Err(err) => {
+                // Nothing after a bad instruction can be decoded reliably.
+                self.head = self.bytes.len();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl Program {
+    /// Decodes the program one instruction at a time, yielding each with
+    /// its offset. Iteration stops after the first error.
+    #[must_use]
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            bytes: &self.bytes,
+            head: 0,
+        }
+    }
+
+    pub fn push_instruction(&mut self, inst: Instruction) {
+        inst.encode(&mut self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::compile_str;
+
+    #[test]
+    fn test_decode() {
+        let mut program = compile_str("1 ?end \"a\" dup * @end");
+        program.push_builtin(Builtin::Print);
+        let insts: Vec<_> = program.instructions().map(Result::unwrap).collect();
+        assert_eq!(
+            insts,
+            [
+                (0, Instruction::LoadConst(0)),
+                (5, Instruction::PopJumpIfFalse(17)),
+                (10, Instruction::LoadConst(1)),
+                (15, Instruction::Dup),
+                (16, Instruction::Mul),
+                (17, Instruction::LoadBuiltin(Builtin::Print)),
+            ]
+        );
+
+        let mut encoded = Program::new();
+        for (_, inst) in insts {
+            encoded.push_instruction(inst);
+        }
+        assert_eq!(encoded.bytes, program.bytes);
+
+        let program = compile_str("rot over pick 3 roll 2 pop_n 4");
+        let insts: Vec<_> = program.instructions().map(Result::unwrap).collect();
+        assert_eq!(
+            insts,
+            [
+                (0, Instruction::Rot),
+                (1, Instruction::Over),
+                (2, Instruction::Pick(3)),
+                (7, Instruction::Roll(2)),
+                (12, Instruction::PopN(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut program = Program::new();
+        program.push_literal(1);
+        program.bytes.extend([OpCode::LoadBuiltin as u8, 9]);
+        let insts: Vec<_> = program.instructions().collect();
+        assert_eq!(
+            insts,
+            [
+                Ok((0, Instruction::LoadConst(0))),
+                Err(DecodeError::InvalidBuiltin { offset: 5, byte: 9 }),
+            ]
+        );
+
+        program.bytes = vec![OpCode::Jump as u8, 0, 0];
+        assert_eq!(
+            program.instructions().next(),
+            Some(Err(DecodeError::Truncated { offset: 0 }))
+        );
+        program.bytes = vec![200];
+        assert_eq!(
+            program.instructions().next(),
+            Some(Err(DecodeError::InvalidOpCode {
+                offset: 0,
+                byte: 200
+            }))
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Jump targets that can be used before the code they point to is pushed.
+
+use std::{fmt, mem};
+
+use crate::{op_codes::OpCode, program::Program};
+
+/// A jump target created by [`Program::new_label`] and placed with
+/// [`Program::bind`]. Jumps to it may be pushed before or after it is bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+/// Returned by [`Program::finish`] when a jump targets a label that was never
+/// bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnboundLabel {
+    pub label: Label,
+    /// Offset of the first jump to the label.
+    pub offset: usize,
+}
+
+impl fmt::Display for UnboundLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "jump at offset {} targets a label that is never bound",
+            self.offset
+        )
+    }
+}
+
+impl std::error::Error for UnboundLabel {}
+
+/// Where each label is bound, and the jumps still waiting for theirs. Like
+/// the interners, this is builder state and is ignored by `==` and `Debug`.
+#[derive(Default, Clone)]
+pub(crate) struct Labels {
+    offsets: Vec<Option<usize>>,
+    /// The operand offset of every jump to a label that is not bound yet.
+    pending: Vec<(Label, usize)>,
+}
+
+impl PartialEq for Labels {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Labels").finish_non_exhaustive()
+    }
+}
+
+impl Program {
+    #[must_use]
+    pub fn new_label(&mut self) -> Label {
+        self.labels.offsets.push(None);
+        Label(self.labels.offsets.len() - 1)
+    }
+
+    /// Binds `label` to the end of the program, patching the jumps already
+    /// pushed to it.
+    ///
+    /// # Panics
+    /// Panics if `label` is already bound.
+    pub fn bind(&mut self, label: Label) {
+        let here = self.len();
+        let offset = &mut self.labels.offsets[label.0];
+        assert!(offset.is_none(), "{label:?} is bound twice");
+        *offset = Some(here);
+        let (ready, pending) = mem::take(&mut self.labels.pending)
+            .into_iter()
+            .partition(|&(waiting, _)| waiting == label);
+        self.labels.pending = pending;
+        for (_, jump) in ready {
+            self.patch_jump(jump);
+        }
+    }
+
+    /// The offset `label` is bound to, if it is bound yet.
+    #[must_use]
+    pub fn label_offset(&self, label: Label) -> Option<usize> {
+        self.labels.offsets[label.0]
+    }
+
+    pub fn jump_to(&mut self, label: Label) {
+        self.push_jump_to(OpCode::Jump, label);
+    }
+    pub fn pop_jump_if_false_to(&mut self, label: Label) {
+        self.push_jump_to(OpCode::PopJumpIfFalse, label);
+    }
+    pub fn pop_jump_if_true_to(&mut self, label: Label) {
+        self.push_jump_to(OpCode::PopJumpIfTrue, label);
+    }
+    pub fn jump_if_false_or_pop_to(&mut self, label: Label) {
+        self.push_jump_to(OpCode::JumpIfFalseOrPop, label);
+    }
+    pub fn jump_if_true_or_pop_to(&mut self, label: Label) {
+        self.push_jump_to(OpCode::JumpIfTrueOrPop, label);
+    }
+
+    fn push_jump_to(&mut self, op: OpCode, label: Label) {
+        let offset = self.label_offset(label);
+        let jump = self.push_jump_op(op, offset.unwrap_or(0));
+        if offset.is_none() {
+            self.labels.pending.push((label, jump));
+        }
+    }
+
+    /// Checks that every label that is jumped to has been bound.
+    ///
+    /// # Errors
+    /// Fails on the first jump to a label that is not bound.
+    pub fn finish(self) -> Result<Self, UnboundLabel> {
+        match self.labels.pending.first() {
+            Some(&(label, jump)) => Err(UnboundLabel {
+                label,
+                offset: jump - 1,
+            }),
+            None => Ok(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{value::Value, vm};
+
+    #[test]
+    fn test_forward_and_backward() {
+        let mut program = Program::new();
+        let start = program.new_label();
+        let end = program.new_label();
+        program.push_literal(0);
+        program.bind(start);
+        program.push_opcode(OpCode::Dup);
+        program.push_literal(3);
+        program.push_opcode(OpCode::Lt);
+        program.pop_jump_if_false_to(end);
+        program.push_literal(1);
+        program.push_opcode(OpCode::Add);
+        program.jump_to(start);
+        program.bind(end);
+
+        let program = program.finish().unwrap();
+        assert_eq!(program.label_offset(start), Some(5));
+        assert_eq!(program.label_offset(end), Some(program.len()));
+        assert_eq!(vm::create_and_run(&program), Ok(vec![Value::Int(3)]));
+    }
+
+    #[test]
+    fn test_unbound() {
+        let mut program = Program::new();
+        let bound = program.new_label();
+        let unbound = program.new_label();
+        program.jump_to(bound);
+        program.push_literal(1);
+        program.jump_if_true_or_pop_to(unbound);
+        program.bind(bound);
+
+        let err = program.finish().unwrap_err();
+        assert_eq!(
+            err,
+            UnboundLabel {
+                label: unbound,
+                offset: 10
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "jump at offset 10 targets a label that is never bound"
+        );
+    }
+}
@@ -1,13 +1,24 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::missing_panics_doc)]
 pub mod assembler;
+pub mod bigint;
 pub mod binops;
 pub mod builtins;
+pub mod cfg;
 mod cursor;
 pub mod dis;
+pub mod dispatch;
+pub mod instruction;
+pub mod label;
+pub mod lines;
 pub mod op_codes;
+pub mod optimize;
 pub mod program;
+pub mod register;
+pub mod serialize;
+pub mod traceback;
 pub mod value;
+pub mod verify;
 pub mod vm;
 
 #[cfg(test)]
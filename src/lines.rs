@@ -0,0 +1,139 @@
+//! Mapping from bytecode offsets back to the source they were assembled from.
+
+use std::fmt;
+
+/// A 1-based line and column in the source. Columns count characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The source location of each range of bytecode. An entry covers the bytes
+/// from its offset up to the offset of the next entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineTable {
+    entries: Vec<(usize, Location)>,
+}
+
+impl LineTable {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new range at `offset`, which must not be before the start
+    /// of the last range. A range with the same offset as the last one
+    /// replaces it.
+    pub fn push(&mut self, offset: usize, location: Location) {
+        match self.entries.last_mut() {
+            Some(last) if last.0 == offset => last.1 = location,
+            Some(last) if last.1 == location => (),
+            _ => self.entries.push((offset, location)),
+        }
+    }
+
+    /// The location of the instruction at `offset`, if it has one.
+    #[must_use]
+    pub fn location(&self, offset: usize) -> Option<Location> {
+        let index = self.entries.partition_point(|&(start, _)| start <= offset);
+        Some(self.entries.get(index.checked_sub(1)?)?.1)
+    }
+
+    /// Drops the ranges that start at or after `len`.
+    pub fn truncate(&mut self, len: usize) {
+        let keep = self.entries.partition_point(|&(start, _)| start < len);
+        self.entries.truncate(keep);
+    }
+
+    #[must_use]
+    pub fn entries(&self) -> &[(usize, Location)] {
+        &self.entries
+    }
+
+    /// Moves every range to the offset `new_offset` gives for its start, for
+    /// when instructions are moved or removed. Ranges that end up empty are
+    /// dropped.
+    #[must_use]
+    pub fn remap(&self, mut new_offset: impl FnMut(usize) -> usize) -> Self {
+        let mut remapped = Self::new();
+        for &(offset, location) in &self.entries {
+            remapped.push(new_offset(offset), location);
+        }
+        remapped
+    }
+}
+
+/// Converts byte offsets into a source string to locations.
+pub(crate) struct SourceMap<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub(crate) fn new(source: &'a str) -> Self {
+        let line_starts = [0]
+            .into_iter()
+            .chain(source.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    pub(crate) fn location(&self, index: usize) -> Location {
+        let line = self.line_starts.partition_point(|&start| start <= index);
+        let start = self.line_starts[line - 1];
+        Location {
+            line: u32::try_from(line).unwrap(),
+            column: u32::try_from(self.source[start..index].chars().count() + 1).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: u32, column: u32) -> Location {
+        Location { line, column }
+    }
+
+    #[test]
+    fn test_location() {
+        let mut table = LineTable::new();
+        table.push(0, at(1, 1));
+        table.push(5, at(1, 3));
+        table.push(5, at(2, 1));
+        table.push(6, at(2, 1));
+        table.push(10, at(3, 4));
+        assert_eq!(
+            table.entries(),
+            [(0, at(1, 1)), (5, at(2, 1)), (10, at(3, 4))]
+        );
+
+        assert_eq!(table.location(0), Some(at(1, 1)));
+        assert_eq!(table.location(4), Some(at(1, 1)));
+        assert_eq!(table.location(6), Some(at(2, 1)));
+        assert_eq!(table.location(100), Some(at(3, 4)));
+        assert_eq!(LineTable::new().location(0), None);
+    }
+
+    #[test]
+    fn test_source_map() {
+        let source = "1 2\n\n  é +\n";
+        let map = SourceMap::new(source);
+        assert_eq!(map.location(0), at(1, 1));
+        assert_eq!(map.location(2), at(1, 3));
+        assert_eq!(map.location(4), at(2, 1));
+        assert_eq!(map.location(7), at(3, 3));
+        assert_eq!(map.location(10), at(3, 5));
+    }
+}
@@ -1,17 +1,124 @@
+use std::time::Instant;
+
 use pettyscript_bytecode::assembler::compile_str;
+use pettyscript_bytecode::cfg::Cfg;
+use pettyscript_bytecode::op_codes::OpCode;
+use pettyscript_bytecode::program::Program;
+use pettyscript_bytecode::register;
 use pettyscript_bytecode::value::Value;
-use pettyscript_bytecode::vm;
+use pettyscript_bytecode::verify::verify;
+use pettyscript_bytecode::vm::{self, Vm, VmError};
+
+const USAGE: &str = "\
+Usage:
+    pty run [--register] <file>
+                            run a program and print the remaining stack, using
+                            the experimental register engine if asked to
+    pty dis [--cfg] <file>  print the disassembly, or the control-flow graph as DOT
+    pty bench <file>        run a program and report instructions per second and
+                            how often each opcode ran";
 
 fn main() {
-    let content = std::fs::read_to_string("examples/while_loop.pty").unwrap();
-    let program = compile_str(&content);
-    eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["run", path] => run(&load(path), vm::create_and_run),
+        ["run", "--register", path] => run(&load(path), register::create_and_run),
+        ["dis", path] => print!("{}", load(path)),
+        ["dis", "--cfg", path] => dis_cfg(&load(path)),
+        ["bench", path] => bench(&load(path)),
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn load(path: &str) -> Program {
+    match std::fs::read_to_string(path) {
+        Ok(content) => compile_str(&content),
+        Err(err) => {
+            eprintln!("Error: {path}: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(program: &Program, engine: fn(&Program) -> Result<Vec<Value>, VmError>) {
+    let stack = match engine(program) {
+        Ok(stack) => stack,
+        Err(err) => fail(&err),
+    };
     if !stack.is_empty() {
         print_stack(&stack);
     }
 }
 
+fn dis_cfg(program: &Program) {
+    match Cfg::new(program) {
+        Ok(cfg) => print!("{}", cfg.to_dot(program)),
+        Err(err) => {
+            eprintln!("Error: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs the program one instruction at a time, counting the instructions
+/// executed per opcode. The timing includes the counting.
+fn bench(program: &Program) {
+    if let Err(err) = verify(program) {
+        eprintln!("Error: {err}");
+        std::process::exit(1);
+    }
+    let mut counts = [0u64; OpCode::StopCode as usize];
+    let mut vm = Vm::from(program);
+    let start = Instant::now();
+    while vm.head() < program.len() {
+        counts[usize::from(program[vm.head()])] += 1;
+        if let Err(err) = vm.run_next() {
+            fail(&err);
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let total: u64 = counts.iter().sum();
+    #[allow(clippy::cast_precision_loss)]
+    let per_second = total as f64 / elapsed;
+    println!(
+        "{total} instructions in {:.3} ms ({:.2} M instructions/s)",
+        elapsed * 1000.0,
+        per_second / 1e6
+    );
+
+    let mut counts: Vec<(OpCode, u64)> = counts
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, count)| count > 0)
+        .map(|(op, count)| (OpCode::try_from(u8::try_from(op).unwrap()).unwrap(), count))
+        .collect();
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    for (op, count) in counts {
+        #[allow(clippy::cast_precision_loss)]
+        let percent = count as f64 * 100.0 / total as f64;
+        println!("{:<16} {count:>12} {percent:>6.2}%", format!("{op:?}"));
+    }
+}
+
+/// Prints a runtime error and exits. The traceback is printed too if the
+/// error was raised inside a function.
+fn fail(err: &VmError) -> ! {
+    let traceback = err.traceback();
+    if traceback.len() > 1 {
+        eprintln!("Traceback (innermost last):");
+        for frame in traceback {
+            eprintln!("  {frame}");
+        }
+    }
+    eprintln!("Error: {err}");
+    std::process::exit(1);
+}
+
 fn print_stack(stack: &[Value]) {
     println!("Remaining stack:");
     for value in stack {
@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum OpCode {
     Nop = 0,
@@ -16,6 +16,16 @@ pub enum OpCode {
     Sub,
     Mul,
     Div,
+    Mod,
+    Pow,
+    IntDiv,
+
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
 
     Le,
     Lt,
@@ -35,6 +45,39 @@ pub enum OpCode {
 
     PopJumpIfFalse,
 
+    // Superinstructions, introduced by `optimize::fuse_instructions`.
+    /// `LoadConst; Add`
+    AddConst,
+    /// `Dup; LoadConst; Lt; PopJumpIfFalse`
+    JumpIfNotLtConst,
+    /// `LoadName; LoadConst; Add; StoreName` on the same name.
+    IncName,
+
+    /// Jumps to a function that returns straight to the current caller, so
+    /// the call stack does not grow.
+    TailCall,
+
+    /// `a b c -- b c a`
+    Rot,
+    /// `a b -- a b a`
+    Over,
+    /// Copies the value the operand places below the top, so `Pick 0` is
+    /// `Dup`.
+    Pick,
+    /// Moves the value the operand places below the top to the top, so
+    /// `Roll 1` is `Swap` and `Roll 2` is `Rot`.
+    Roll,
+    /// Pops as many values as the operand.
+    PopN,
+
+    PopJumpIfTrue,
+    /// Jumps if the value on top of the stack is falsy, leaving it there, and
+    /// pops it otherwise.
+    JumpIfFalseOrPop,
+    /// Jumps if the value on top of the stack is truthy, leaving it there,
+    /// and pops it otherwise.
+    JumpIfTrueOrPop,
+
     StopCode,
 }
 
@@ -44,21 +87,81 @@ impl OpCode {
     pub fn size_operand(self) -> usize {
         match self {
             Self::Nop | Self::Dup | Self::Pop | Self::Swap | Self::DupSwap => 0,
+            Self::Rot | Self::Over => 0,
             Self::PrepareFuncCall | Self::Ret => 0,
             Self::Add | Self::Sub | Self::Mul | Self::Div | Self::UnaryNot => 0,
+            Self::Mod | Self::Pow | Self::IntDiv => 0,
+            Self::BitAnd | Self::BitOr | Self::BitXor | Self::BitNot => 0,
+            Self::Shl | Self::Shr => 0,
             Self::Le | Self::Lt | Self::Ge | Self::Gt | Self::Eq | Self::Ne => 0,
 
             Self::LoadBuiltin => 1,
 
             Self::LoadConst | Self::StoreName | Self::LoadName => 4,
             Self::PopJumpIfFalse | Self::Jump => 4,
+            Self::PopJumpIfTrue | Self::JumpIfFalseOrPop | Self::JumpIfTrueOrPop => 4,
+            Self::AddConst | Self::TailCall => 4,
+            Self::Pick | Self::Roll | Self::PopN => 4,
+            Self::JumpIfNotLtConst | Self::IncName => 8,
             Self::StopCode => 0,
         }
     }
+
+    /// How many values the instruction pops and then pushes, or `None` for
+    /// `LoadBuiltin`, `Pick`, `Roll` and `PopN`, whose effect depends on the
+    /// operand.
+    #[allow(clippy::match_same_arms)]
+    #[must_use]
+    pub fn stack_effect(self) -> Option<StackEffect> {
+        let (pops, pushes) = match self {
+            Self::Nop | Self::Jump | Self::Ret | Self::PrepareFuncCall | Self::TailCall => (0, 0),
+            Self::Dup => (1, 2),
+            Self::Pop => (1, 0),
+            Self::Swap => (2, 2),
+            Self::DupSwap => (2, 3),
+            Self::Rot => (3, 3),
+            Self::Over => (2, 3),
+            Self::Add | Self::Sub | Self::Mul | Self::Div => (2, 1),
+            Self::Mod | Self::Pow | Self::IntDiv => (2, 1),
+            Self::BitAnd | Self::BitOr | Self::BitXor | Self::Shl | Self::Shr => (2, 1),
+            Self::Le | Self::Lt | Self::Ge | Self::Gt | Self::Eq | Self::Ne => (2, 1),
+            Self::UnaryNot | Self::BitNot => (1, 1),
+            Self::LoadConst | Self::LoadName => (0, 1),
+            Self::StoreName | Self::PopJumpIfFalse | Self::PopJumpIfTrue => (1, 0),
+            // The value is only popped if the jump is not taken.
+            Self::JumpIfFalseOrPop | Self::JumpIfTrueOrPop => (1, 1),
+            Self::AddConst | Self::JumpIfNotLtConst => (1, 1),
+            Self::IncName => (0, 0),
+            Self::LoadBuiltin | Self::Pick | Self::Roll | Self::PopN => return None,
+            Self::StopCode => (0, 0),
+        };
+        Some(StackEffect { pops, pushes })
+    }
+
+    /// Whether one of the operands is the byte offset of a jump target.
+    #[must_use]
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            Self::Jump
+                | Self::PopJumpIfFalse
+                | Self::JumpIfNotLtConst
+                | Self::TailCall
+                | Self::PopJumpIfTrue
+                | Self::JumpIfFalseOrPop
+                | Self::JumpIfTrueOrPop
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub pops: usize,
+    pub pushes: usize,
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct InvalidOpCode(u8);
+pub struct InvalidOpCode(pub u8);
 
 impl TryFrom<u8> for OpCode {
     type Error = InvalidOpCode;
@@ -68,6 +171,6 @@ impl TryFrom<u8> for OpCode {
         }
         // # Safety:
         // OpCode is repr(u8) and value is guaranteed to be < OpCode's last variant.
-        Ok(unsafe { std::mem::transmute(value) })
+        Ok(unsafe { std::mem::transmute::<u8, Self>(value) })
     }
 }
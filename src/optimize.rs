@@ -0,0 +1,1020 @@
+use std::mem;
+
+use crate::{
+    binops::{binary_op, unary_op},
+    instruction::Instruction,
+    program::Program,
+    value::Value,
+};
+
+/// Rewrites short instruction sequences that have no effect or can be done
+/// in fewer steps:
+///
+/// - `LoadConst; Pop`, `Dup; Pop` and `Swap; Swap` are removed.
+/// - A `Jump` to the instruction right after it is removed.
+/// - A jump to a `Jump` is re-targeted to that jump's destination.
+///
+/// Programs that fail to decode are returned unchanged.
+#[must_use]
+pub fn peephole(program: &Program) -> Program {
+    let Some(mut code) = Code::decode(program) else {
+        return program.clone();
+    };
+    while code.remove_noop_pairs() | code.thread_jumps() | code.remove_jumps_to_next() {}
+    let mut optimized = program.clone();
+    code.write(&mut optimized);
+    optimized
+}
+
+/// Evaluates operators whose operands are constants at compile time:
+///
+/// - `LoadConst; LoadConst; <binary op>` and `LoadConst; <unary op>` become a
+///   single `LoadConst` of the result, unless evaluating the operator fails
+///   or gives a large string or integer. Those are left to the runtime.
+/// - `LoadConst` followed by a conditional jump becomes a `Jump`, keeping
+///   the `LoadConst` if the jump would not pop it, when the jump is taken and
+///   is removed otherwise.
+///
+/// Operators are evaluated by the same functions the VM uses. Constants that
+/// are no longer loaded are removed from the constant pool afterwards.
+#[must_use]
+pub fn fold_constants(program: &Program) -> Program {
+    let Some(mut code) = Code::decode(program) else {
+        return program.clone();
+    };
+    let mut folded = program.clone();
+    code.fold(&mut folded);
+    folded.constants = code.collect_constants(&folded.constants);
+    code.write(&mut folded);
+    folded
+}
+
+/// Replaces common instruction sequences with a single superinstruction:
+///
+/// - `LoadName n; LoadConst; Add; StoreName n` becomes `IncName`.
+/// - `Dup; LoadConst; Lt; PopJumpIfFalse` becomes `JumpIfNotLtConst`.
+/// - `LoadConst; Add` becomes `AddConst`.
+///
+/// A sequence is only fused if no jump or return lands inside it. Programs
+/// that fail to decode are returned unchanged.
+#[must_use]
+pub fn fuse_instructions(program: &Program) -> Program {
+    let Some(mut code) = Code::decode(program) else {
+        return program.clone();
+    };
+    code.fuse();
+    let mut optimized = program.clone();
+    code.write(&mut optimized);
+    optimized
+}
+
+/// Turns `PrepareFuncCall; Jump; Ret`, a call whose result is returned
+/// straight away, into a `TailCall` so the call stack does not grow. The
+/// `Ret` is kept if something else jumps to it.
+///
+/// Programs that fail to decode are returned unchanged.
+#[must_use]
+pub fn eliminate_tail_calls(program: &Program) -> Program {
+    let Some(mut code) = Code::decode(program) else {
+        return program.clone();
+    };
+    code.tail_calls();
+    let mut optimized = program.clone();
+    code.write(&mut optimized);
+    optimized
+}
+
+/// Removes every instruction that cannot be reached from the start of the
+/// program, following calls into the functions they enter, returning the
+/// compacted program and the number of bytes removed. A function that is only
+/// called from unreachable code is removed too.
+///
+/// Programs that fail to decode are returned unchanged.
+#[must_use]
+pub fn eliminate_dead_code(program: &Program) -> (Program, usize) {
+    let Some(mut code) = Code::decode(program) else {
+        return (program.clone(), 0);
+    };
+    code.remove_unreachable();
+    let mut optimized = program.clone();
+    code.write(&mut optimized);
+    let removed = program.len() - optimized.len();
+    (optimized, removed)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Inst {
+    /// Jump targets are the index of the target instruction.
+    inst: Instruction,
+    removed: bool,
+}
+
+/// A program decoded into instructions. Jump operands are stored as
+/// instruction indices so instructions can be removed without invalidating
+/// them; a target of `insts.len()` is the end of the program.
+struct Code {
+    insts: Vec<Inst>,
+    /// The offset each instruction was decoded from, and the length of the
+    /// program.
+    offsets: Vec<usize>,
+}
+
+impl Code {
+    fn decode(program: &Program) -> Option<Self> {
+        let decoded = program.instructions().collect::<Result<Vec<_>, _>>().ok()?;
+        let offsets: Vec<usize> = decoded
+            .iter()
+            .map(|&(offset, _)| offset)
+            .chain([program.len()])
+            .collect();
+        let mut insts = Vec::with_capacity(decoded.len());
+        for (_, inst) in decoded {
+            let inst = match inst.jump_target() {
+                Some(target) => {
+                    let index = offsets.binary_search(&(target as usize)).ok()?;
+                    inst.with_jump_target(u32::try_from(index).ok()?)
+                }
+                None => inst,
+            };
+            insts.push(Inst {
+                inst,
+                removed: false,
+            });
+        }
+        Some(Self { insts, offsets })
+    }
+
+    /// Replaces the bytecode of `program` with the instructions that have
+    /// not been removed, moving its line table and function names to match.
+    fn write(&self, program: &mut Program) {
+        let offsets = self.new_offsets();
+        program.bytes = self.encode(&offsets);
+        let new_offset = |offset| offsets[self.offsets.partition_point(|&start| start < offset)];
+        if let Some(lines) = &program.lines {
+            let mut lines = lines.remap(new_offset);
+            lines.truncate(program.len());
+            program.lines = Some(lines);
+        }
+        // A function whose entry was removed keeps its name only if it is
+        // still jumped to, which means its code now starts later.
+        let targets: Vec<usize> = self
+            .live()
+            .into_iter()
+            .filter_map(|i| self.target(i))
+            .collect();
+        program.func_names = mem::take(&mut program.func_names)
+            .into_iter()
+            .filter_map(|(entry, name)| {
+                let index = self.offsets.binary_search(&entry).ok()?;
+                let dropped = self.insts.get(index)?.removed && !targets.contains(&index);
+                (!dropped).then(|| (offsets[index], name))
+            })
+            .collect();
+    }
+
+    /// The offset of each instruction once the removed ones are dropped. A
+    /// removed instruction gets the offset of the next one that is kept.
+    fn new_offsets(&self) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(self.insts.len() + 1);
+        let mut head = 0;
+        for inst in &self.insts {
+            offsets.push(head);
+            if !inst.removed {
+                head += inst.inst.size();
+            }
+        }
+        offsets.push(head);
+        offsets
+    }
+
+    fn encode(&self, offsets: &[usize]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(offsets[self.insts.len()]);
+        for Inst { inst, .. } in self.insts.iter().filter(|inst| !inst.removed) {
+            let inst = match inst.jump_target() {
+                Some(index) => {
+                    inst.with_jump_target(u32::try_from(offsets[index as usize]).unwrap())
+                }
+                None => *inst,
+            };
+            inst.encode(&mut bytes);
+        }
+        bytes
+    }
+
+    /// The index of the instruction the `i`th instruction jumps to.
+    fn target(&self, i: usize) -> Option<usize> {
+        self.insts[i].inst.jump_target().map(|index| index as usize)
+    }
+
+    /// The first instruction at or after `index` that has not been removed.
+    fn resolve(&self, mut index: usize) -> usize {
+        while self.insts.get(index).is_some_and(|inst| inst.removed) {
+            index += 1;
+        }
+        index
+    }
+
+    /// Indices of the instructions that have not been removed.
+    fn live(&self) -> Vec<usize> {
+        (0..self.insts.len())
+            .filter(|&i| !self.insts[i].removed)
+            .collect()
+    }
+
+    /// Marks every instruction that can be entered other than by falling
+    /// through from the one before it: jump targets and the return sites of
+    /// function calls.
+    fn entries(&self) -> Vec<bool> {
+        self.entry_counts()
+            .into_iter()
+            .map(|count| count > 0)
+            .collect()
+    }
+
+    /// How many jumps and calls enter each instruction other than by falling
+    /// through from the one before it.
+    fn entry_counts(&self) -> Vec<u32> {
+        let mut entries = vec![0; self.insts.len() + 1];
+        let live = self.live();
+        for (pos, &i) in live.iter().enumerate() {
+            if let Some(target) = self.target(i) {
+                entries[self.resolve(target)] += 1;
+            }
+            if self.is_call(&live, pos) {
+                entries[live.get(pos + 1).copied().unwrap_or(self.insts.len())] += 1;
+            }
+        }
+        entries
+    }
+
+    /// Whether `live[pos]` is the `Jump` of a `PrepareFuncCall; Jump` pair,
+    /// which must stay in place since the return address is computed from it.
+    fn is_call(&self, live: &[usize], pos: usize) -> bool {
+        pos > 0
+            && matches!(self.insts[live[pos]].inst, Instruction::Jump(_))
+            && matches!(self.insts[live[pos - 1]].inst, Instruction::PrepareFuncCall)
+    }
+
+    fn remove_unreachable(&mut self) {
+        let live = self.live();
+        let position = |index: usize| live.binary_search(&self.resolve(index)).ok();
+
+        let mut reachable = vec![false; live.len()];
+        let mut stack = vec![0];
+        while let Some(pos) = stack.pop() {
+            if pos >= live.len() || reachable[pos] {
+                continue;
+            }
+            reachable[pos] = true;
+            // Jumps, including those of calls and tail calls, reach their target.
+            stack.extend(self.target(live[pos]).and_then(position));
+            // Calls continue after the jump once the function returns.
+            let falls_through = match self.insts[live[pos]].inst {
+                Instruction::Jump(_) => self.is_call(&live, pos),
+                Instruction::Ret | Instruction::TailCall(_) => false,
+                _ => true,
+            };
+            if falls_through {
+                stack.push(pos + 1);
+            }
+        }
+
+        for (pos, &i) in live.iter().enumerate() {
+            if !reachable[pos] {
+                self.insts[i].removed = true;
+            }
+        }
+    }
+
+    fn remove_noop_pairs(&mut self) -> bool {
+        let entries = self.entries();
+        let live = self.live();
+        let mut changed = false;
+        let mut pos = 0;
+        while pos + 1 < live.len() {
+            let (first, second) = (live[pos], live[pos + 1]);
+            let noop = matches!(
+                (self.insts[first].inst, self.insts[second].inst),
+                (
+                    Instruction::LoadConst(_) | Instruction::Dup,
+                    Instruction::Pop
+                ) | (Instruction::Swap, Instruction::Swap)
+            );
+            if noop && !entries[second] {
+                self.insts[first].removed = true;
+                self.insts[second].removed = true;
+                changed = true;
+                pos += 2;
+            } else {
+                pos += 1;
+            }
+        }
+        changed
+    }
+
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for i in self.live() {
+            let Some(original) = self.target(i) else {
+                continue;
+            };
+            let mut target = self.resolve(original);
+            let mut seen = vec![i];
+            while let Some(Inst {
+                inst: Instruction::Jump(next),
+                ..
+            }) = self.insts.get(target)
+            {
+                if seen.contains(&target) {
+                    break;
+                }
+                seen.push(target);
+                target = self.resolve(*next as usize);
+            }
+            // A chain that loops back on itself is left alone.
+            if seen.contains(&target) {
+                continue;
+            }
+            if target != self.resolve(original) {
+                let inst = &mut self.insts[i].inst;
+                *inst = inst.with_jump_target(u32::try_from(target).unwrap());
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Folds every foldable sequence in one scan, adding the results to the
+    /// constants of `program`. A fold only creates new opportunities around
+    /// where it happened, so only those instructions are scanned again.
+    fn fold(&mut self, program: &mut Program) {
+        let live = self.live();
+        let mut state = Folding::new(self, &live);
+        let mut pending: Vec<usize> = live.into_iter().rev().collect();
+        while let Some(first) = pending.pop() {
+            if !self.insts[first].removed {
+                self.fold_at(program, &mut state, first, &mut pending);
+            }
+        }
+    }
+
+    /// Folds the sequence starting at `first` if it is foldable, pushing the
+    /// instructions that must be scanned again onto `pending`.
+    fn fold_at(
+        &mut self,
+        program: &mut Program,
+        state: &mut Folding,
+        first: usize,
+        pending: &mut Vec<usize>,
+    ) {
+        let load = |code: &Self, i: usize| match code.insts[i].inst {
+            Instruction::LoadConst(index) => Some(program.constants[index as usize].clone()),
+            _ => None,
+        };
+        let Some(lhs) = load(self, first) else { return };
+        let Some(second) = state.next(first) else {
+            return;
+        };
+        if let Some(func) = unary_op(self.insts[second].inst.op_code()) {
+            let Some(result) = func(lhs).ok().filter(is_small) else {
+                return;
+            };
+            self.insts[first].inst = load_const(program, result);
+            state.remove(self, second);
+            pending.extend(state.and_before(first, 1));
+            return;
+        }
+        // The target of a conditional jump, when it jumps and whether it pops.
+        let (target, when, pops) = match self.insts[second].inst {
+            Instruction::PopJumpIfFalse(target) => (target, false, true),
+            Instruction::PopJumpIfTrue(target) => (target, true, true),
+            Instruction::JumpIfFalseOrPop(target) => (target, false, false),
+            Instruction::JumpIfTrueOrPop(target) => (target, true, false),
+            _ => {
+                let Some(rhs) = load(self, second) else {
+                    return;
+                };
+                let Some(third) = state.next(second) else {
+                    return;
+                };
+                let Some(func) = binary_op(self.insts[third].inst.op_code()) else {
+                    return;
+                };
+                let Some(result) = func(lhs, rhs).ok().filter(is_small) else {
+                    return;
+                };
+                self.insts[first].inst = load_const(program, result);
+                state.remove(self, second);
+                state.remove(self, third);
+                pending.extend(state.and_before(first, 1));
+                return;
+            }
+        };
+        if bool::from(&lhs) == when {
+            // A `PopJumpIf*` jumps without its operand, a `Jump*OrPop` keeps it.
+            if pops {
+                self.insts[first].inst = Instruction::Jump(target);
+                state.remove(self, second);
+                state.mark_call(self, state.prev[first]);
+            } else {
+                self.insts[second].inst = Instruction::Jump(target);
+            }
+            return;
+        }
+        // The jump is never taken, so its target may no longer be an entry.
+        let target = target as usize;
+        let resolved = state.resolve(target);
+        state.entries[resolved] -= 1;
+        let before = state.prev[first];
+        state.remove(self, first);
+        state.remove(self, second);
+        state.mark_call(self, before);
+        let target = state.resolve(target);
+        if state.entries[target] == 0 && target < self.insts.len() {
+            pending.extend(state.and_before(target, 2));
+        }
+        if let Some(before) = before {
+            pending.extend(state.and_before(before, 2));
+        }
+    }
+
+    fn fuse(&mut self) {
+        use Instruction::{Add, Dup, LoadConst, LoadName, Lt, PopJumpIfFalse, StoreName};
+
+        let entries = self.entries();
+        let live = self.live();
+        let mut pos = 0;
+        while pos < live.len() {
+            // The instructions following `pos` that may be fused with it.
+            let window: Vec<Instruction> = live[pos..]
+                .iter()
+                .take(4)
+                .enumerate()
+                .take_while(|&(n, &i)| n == 0 || !entries[i])
+                .map(|(_, &i)| self.insts[i].inst)
+                .collect();
+            let (fused, len) = match window[..] {
+                [LoadName(load), LoadConst(index), Add, StoreName(store), ..] if load == store => {
+                    (Instruction::IncName(load, index), 4)
+                }
+                [Dup, LoadConst(index), Lt, PopJumpIfFalse(target), ..] => {
+                    (Instruction::JumpIfNotLtConst(index, target), 4)
+                }
+                [LoadConst(index), Add, ..] => (Instruction::AddConst(index), 2),
+                _ => {
+                    pos += 1;
+                    continue;
+                }
+            };
+            self.insts[live[pos]].inst = fused;
+            for &i in &live[pos + 1..pos + len] {
+                self.insts[i].removed = true;
+            }
+            pos += len;
+        }
+    }
+
+    /// Drops constants that are no longer loaded, keeping the rest in order,
+    /// and renumbers the constant operands to match.
+    fn collect_constants(&mut self, constants: &[Value]) -> Vec<Value> {
+        let mut used = vec![false; constants.len()];
+        for inst in self.insts.iter().filter(|inst| !inst.removed) {
+            if let Some(index) = inst.inst.constant() {
+                used[index as usize] = true;
+            }
+        }
+        let mut remap = vec![0; constants.len()];
+        let mut collected = vec![];
+        for (index, constant) in constants.iter().enumerate() {
+            if used[index] {
+                remap[index] = u32::try_from(collected.len()).unwrap();
+                collected.push(constant.clone());
+            }
+        }
+        for inst in self.insts.iter_mut().filter(|inst| !inst.removed) {
+            if let Some(index) = inst.inst.constant() {
+                inst.inst = inst.inst.with_constant(remap[index as usize]);
+            }
+        }
+        collected
+    }
+
+    fn tail_calls(&mut self) {
+        let live = self.live();
+        let jumped_to: Vec<usize> = live
+            .iter()
+            .filter_map(|&i| self.target(i))
+            .map(|target| self.resolve(target))
+            .collect();
+        for pos in 1..live.len().saturating_sub(1) {
+            let (prepare, jump, ret) = (live[pos - 1], live[pos], live[pos + 1]);
+            if !self.is_call(&live, pos)
+                || self.insts[ret].inst != Instruction::Ret
+                || jumped_to.contains(&jump)
+            {
+                continue;
+            }
+            let target = self.target(jump).unwrap();
+            self.insts[prepare].inst = Instruction::TailCall(u32::try_from(target).unwrap());
+            self.insts[jump].removed = true;
+            self.insts[ret].removed = !jumped_to.contains(&ret);
+        }
+    }
+
+    fn remove_jumps_to_next(&mut self) -> bool {
+        let live = self.live();
+        let mut changed = false;
+        for (pos, &i) in live.iter().enumerate() {
+            let next = live.get(pos + 1).copied().unwrap_or(self.insts.len());
+            if matches!(self.insts[i].inst, Instruction::Jump(_))
+                && self.target(i).map(|target| self.resolve(target)) == Some(next)
+                && !self.is_call(&live, pos)
+            {
+                self.insts[i].removed = true;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// The live instructions of a [`Code`] being folded, kept as a linked list so
+/// instructions can be removed while scanning, and how many jumps and calls
+/// enter each of them.
+struct Folding {
+    prev: Vec<Option<usize>>,
+    next: Vec<Option<usize>>,
+    /// Where to look for the first live instruction at or after an index.
+    skip: Vec<usize>,
+    entries: Vec<u32>,
+}
+
+impl Folding {
+    fn new(code: &Code, live: &[usize]) -> Self {
+        let len = code.insts.len();
+        let mut state = Self {
+            prev: vec![None; len],
+            next: vec![None; len],
+            skip: (0..=len).collect(),
+            entries: code.entry_counts(),
+        };
+        for pair in live.windows(2) {
+            state.next[pair[0]] = Some(pair[1]);
+            state.prev[pair[1]] = Some(pair[0]);
+        }
+        for i in (0..len).filter(|&i| code.insts[i].removed) {
+            state.skip[i] = i + 1;
+        }
+        state
+    }
+
+    /// The live instruction after `i`, if falling through is the only way to
+    /// enter it.
+    fn next(&self, i: usize) -> Option<usize> {
+        self.next[i].filter(|&next| self.entries[next] == 0)
+    }
+
+    /// `i` and up to `n` live instructions before it, the earliest last.
+    fn and_before(&self, i: usize, n: usize) -> Vec<usize> {
+        std::iter::successors(Some(i), |&i| self.prev[i])
+            .take(n + 1)
+            .collect()
+    }
+
+    /// The first live instruction at or after `index`.
+    fn resolve(&mut self, mut index: usize) -> usize {
+        while self.skip[index] != index {
+            let next = self.skip[index];
+            self.skip[index] = self.skip[next];
+            index = next;
+        }
+        index
+    }
+
+    /// Removes the `i`th instruction. Jumps to it now enter the instruction
+    /// after it.
+    fn remove(&mut self, code: &mut Code, i: usize) {
+        code.insts[i].removed = true;
+        let (prev, next) = (self.prev[i], self.next[i]);
+        if let Some(prev) = prev {
+            self.next[prev] = next;
+        }
+        if let Some(next) = next {
+            self.prev[next] = prev;
+        }
+        self.skip[i] = i + 1;
+        self.entries[next.unwrap_or(code.insts.len())] += mem::take(&mut self.entries[i]);
+    }
+
+    /// Marks the return site of a call if the `i`th instruction is a
+    /// `PrepareFuncCall` that was just brought next to a `Jump`.
+    fn mark_call(&mut self, code: &Code, i: Option<usize>) {
+        let Some(call) = i.filter(|&i| matches!(code.insts[i].inst, Instruction::PrepareFuncCall))
+        else {
+            return;
+        };
+        if let Some(jump) = self.next[call] {
+            if matches!(code.insts[jump].inst, Instruction::Jump(_)) {
+                self.entries[self.next[jump].unwrap_or(code.insts.len())] += 1;
+            }
+        }
+    }
+}
+
+/// The largest string or integer, in bytes, that folding may add to the
+/// constant pool.
+const MAX_FOLDED_SIZE: usize = 256;
+
+/// Whether `value` is small enough to be worth storing as a folded constant.
+/// Larger results, like `"a" 1000000 *`, are left to be built at runtime.
+fn is_small(value: &Value) -> bool {
+    match value {
+        Value::Str(str) => str.len() <= MAX_FOLDED_SIZE,
+        Value::BigInt(int) => int.limbs().len() * 4 <= MAX_FOLDED_SIZE,
+        Value::Int(_) | Value::Float(_) => true,
+    }
+}
+
+fn load_const(program: &mut Program, value: Value) -> Instruction {
+    Instruction::LoadConst(u32::try_from(program.add_constant(value)).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::compile_str, op_codes::OpCode, vm};
+
+    fn assert_same_result(program: &Program) -> Program {
+        let optimized = peephole(program);
+        eprintln!("{program}\n{optimized}");
+        assert_eq!(vm::create_and_run(&optimized), vm::create_and_run(program));
+        optimized
+    }
+
+    #[test]
+    fn test_removes_noop_pairs() {
+        let program = compile_str("1 2 pop dup pop 3 swap swap +");
+        let optimized = assert_same_result(&program);
+        assert_eq!(optimized.len(), compile_str("1 3 +").len());
+    }
+
+    #[test]
+    fn test_keeps_pair_split_by_jump_target() {
+        // The `pop` is reached by the jump with only one value on the stack.
+        let program = compile_str("1 0 ?skip 2 @skip pop");
+        let optimized = assert_same_result(&program);
+        assert_eq!(optimized.len(), program.len());
+    }
+
+    #[test]
+    fn test_removes_jump_to_next() {
+        let program = compile_str("1 $next @next 2 +");
+        let optimized = assert_same_result(&program);
+        assert_eq!(optimized.len(), program.len() - 5);
+    }
+
+    #[test]
+    fn test_threads_jumps() {
+        let mut program = Program::new();
+        let first = program.push_jump(0);
+        program.push_literal("skipped");
+        program.patch_jump(first);
+        let second = program.push_jump(0);
+        program.push_literal("skipped");
+        program.patch_jump(second);
+        program.push_literal("reached");
+
+        let optimized = assert_same_result(&program);
+        // Both jumps end up going straight to the end, so the second one is
+        // skipped over and the first one's target moves past it.
+        let target = u32::from_le_bytes(optimized.read_arr(1).unwrap()) as usize;
+        assert_eq!(target, optimized.len() - 5);
+    }
+
+    #[test]
+    fn test_leaves_self_loops() {
+        let program = compile_str("@a $b 1 @b $a");
+        let optimized = peephole(&program);
+        assert_eq!(optimized.len(), program.len());
+    }
+
+    #[test]
+    fn test_while_loop() {
+        let program = compile_str(include_str!("../examples/while_loop.pty"));
+        assert_same_result(&program);
+    }
+
+    #[test]
+    fn test_if_else_and_functions() {
+        let mut program = Program::new();
+        let func = program.push_func(|func| {
+            func.push_opcode(OpCode::Dup);
+            func.push_opcode(OpCode::Pop);
+            func.push_literal(1);
+            func.push_opcode(OpCode::Add);
+        });
+        program.push_literal(3);
+        program.call_func(func);
+        program.push_opcode(OpCode::Dup);
+        program.push_if_or_else(
+            |body| {
+                body.push_literal("yes");
+                body.push_jump(body.len() + 5);
+            },
+            |orelse| {
+                orelse.push_literal("no");
+                orelse.push_opcode(OpCode::Swap);
+                orelse.push_opcode(OpCode::Swap);
+            },
+        );
+        program.push_literal(0);
+        program.push_opcode(OpCode::Pop);
+
+        let optimized = assert_same_result(&program);
+        assert!(optimized.len() < program.len());
+    }
+
+    fn assert_same_fold(program: &Program) -> Program {
+        let folded = fold_constants(program);
+        eprintln!("{program}\n{folded}");
+        assert_eq!(vm::create_and_run(&folded), vm::create_and_run(program));
+        folded
+    }
+
+    #[test]
+    fn test_fold_arithmetic() {
+        let program = compile_str("1 2 + 3 * 2 ** ~ \"a\" 3 *");
+        let folded = assert_same_fold(&program);
+        assert_eq!(folded.len(), 10);
+        assert_eq!(folded.constants, [Value::Int(-82), Value::from("aaa")]);
+    }
+
+    #[test]
+    fn test_fold_leaves_errors_to_runtime() {
+        let program = compile_str("1 0 % \"a\" 1 <");
+        let folded = assert_same_fold(&program);
+        assert_eq!(folded.bytes, program.bytes);
+        assert!(vm::create_and_run(&folded).is_err());
+    }
+
+    #[test]
+    fn test_fold_leaves_large_results_to_runtime() {
+        let program =
+            compile_str("2 4000000000 ** \"a\" 9223372036854775807 * \"a\" 300 * 2 3000 **");
+        let folded = fold_constants(&program);
+        assert_eq!(folded.bytes, program.bytes);
+        assert_eq!(folded.constants, program.constants);
+
+        let program = compile_str("\"ab\" 3 * 2 100 **");
+        let folded = assert_same_fold(&program);
+        assert_eq!(folded.len(), 10);
+    }
+
+    #[test]
+    fn test_fold_cascades() {
+        // Each fold makes the one before or after it possible.
+        let source = ["1"; 1000].join(" ") + &" +".repeat(999) + &" 2 *".repeat(1000);
+        let folded = assert_same_fold(&compile_str(&source));
+        assert_eq!(folded.len(), 5);
+        assert_eq!(folded.constants.len(), 1);
+    }
+    #[test]
+    fn test_fold_stops_at_jump_targets() {
+        // `+` is reached from the jump with a different left operand.
+        let program = compile_str("5 dup ?skip pop 1 @skip 2 +");
+        let folded = assert_same_fold(&program);
+        assert!(folded.to_string().contains("Add"));
+    }
+
+    #[test]
+    fn test_fold_conditional_jumps() {
+        let mut program = Program::new();
+        program.push_literal(0);
+        program.push_if_or_else(
+            |body| _ = body.push_literal("then"),
+            |orelse| _ = orelse.push_literal("else"),
+        );
+        program.push_literal(1);
+        program.push_if_or_else(
+            |body| _ = body.push_literal("if"),
+            |orelse| _ = orelse.push_literal("not"),
+        );
+
+        let folded = assert_same_fold(&program);
+        assert!(!folded.to_string().contains("PopJumpIfFalse"));
+        assert_eq!(
+            vm::create_and_run(&folded).unwrap(),
+            [Value::from("else"), Value::from("if")]
+        );
+
+        let program = compile_str("0 &&a 1 @a 2 ||b 3 @b 4 !?c 5 pop @c");
+        let folded = assert_same_fold(&program);
+        assert!(!folded.to_string().contains("If"));
+        assert_eq!(
+            vm::create_and_run(&folded).unwrap(),
+            [Value::Int(0), Value::Int(2)]
+        );
+    }
+
+    #[test]
+    fn test_fold_while_loop() {
+        let program = compile_str("0 @start dup 2 3 + < ?end 1 + $start @end");
+        let folded = assert_same_fold(&program);
+        assert_eq!(
+            folded.constants,
+            [Value::Int(0), Value::Int(1), Value::Int(5)]
+        );
+    }
+
+    fn assert_same_fused(program: &Program) -> Program {
+        let fused = fuse_instructions(program);
+        eprintln!("{program}\n{fused}");
+        assert_eq!(vm::create_and_run(&fused), vm::create_and_run(program));
+        fused
+    }
+
+    #[test]
+    fn test_fuse_while_loop() {
+        let program = compile_str(include_str!("../examples/while_loop.pty"));
+        let fused = assert_same_fused(&program);
+        let dis = fused.to_string();
+        assert!(dis.contains("10 JumpIfNotLtConst 2 7 32"));
+        assert!(dis.contains("19 AddConst 0 1"));
+        assert_eq!(fused.len(), program.len() - 4);
+    }
+
+    #[test]
+    fn test_fuse_inc_name() {
+        let mut program = Program::new();
+        program.push_literal(0);
+        program.store_name("i");
+        program.load_name("i");
+        program.push_literal(2);
+        program.push_opcode(OpCode::Add);
+        program.store_name("i");
+        // Adding to one name and storing to another is not an increment.
+        program.load_name("i");
+        program.push_literal(3);
+        program.push_opcode(OpCode::Add);
+        program.store_name("j");
+        program.load_name("i");
+        program.load_name("j");
+
+        let fused = assert_same_fused(&program);
+        let dis = fused.to_string();
+        assert!(dis.contains("IncName 0 i 1 2"));
+        assert!(dis.contains("AddConst 2 3"));
+        assert_eq!(
+            vm::create_and_run(&fused).unwrap(),
+            [Value::Int(2), Value::Int(5)]
+        );
+    }
+
+    #[test]
+    fn test_fuse_stops_at_jump_targets() {
+        // `+` is reached from the jump with `2` on top instead of `3`.
+        let program = compile_str("1 2 0 ?add pop 3 @add +");
+        let fused = assert_same_fused(&program);
+        assert_eq!(fused.bytes, program.bytes);
+    }
+
+    #[test]
+    fn test_fold_after_fuse() {
+        // Constants read by superinstructions are kept.
+        let program = fuse_instructions(&compile_str("1 2 3 + +"));
+        let folded = assert_same_fold(&program);
+        assert_eq!(folded.constants.len(), 3);
+    }
+
+    #[test]
+    fn test_tail_call_keeps_jumped_to_ret() {
+        let mut program = Program::new();
+        let func = program.push_func(|func| {
+            let entry = func.len();
+            func.push_opcode(OpCode::Dup);
+            let skip = func.push_pop_jump_if_false(0);
+            func.push_literal(1);
+            func.push_opcode(OpCode::Sub);
+            func.call_func(entry);
+            func.patch_jump(skip);
+            func.push_opcode(OpCode::Ret);
+        });
+        program.push_literal(3);
+        program.call_func(func);
+
+        let optimized = eliminate_tail_calls(&program);
+        eprintln!("{program}\n{optimized}");
+        // Only `PrepareFuncCall; Jump` is replaced, as the skip lands on the `Ret`.
+        assert!(optimized.to_string().contains("TailCall"));
+        assert_eq!(optimized.len(), program.len() - 1);
+        assert_eq!(vm::create_and_run(&optimized), vm::create_and_run(&program));
+    }
+
+    #[test]
+    fn test_dead_code_after_jump() {
+        let program = compile_str("1 $end 2 3 + @end 4");
+        let (optimized, removed) = eliminate_dead_code(&program);
+        eprintln!("{program}\n{optimized}");
+        assert_eq!(removed, 11);
+        assert_eq!(optimized.len(), program.len() - removed);
+        assert_eq!(vm::create_and_run(&optimized), vm::create_and_run(&program));
+    }
+
+    #[test]
+    fn test_dead_code_after_ret() {
+        let mut program = Program::new();
+        let func = program.push_func(|func| {
+            func.push_opcode(OpCode::Dup);
+            func.push_if_or_else(
+                |body| body.push_opcode(OpCode::Ret),
+                |orelse| _ = orelse.push_literal(10),
+            );
+            func.push_opcode(OpCode::Add);
+        });
+        program.push_literal(0);
+        program.call_func(func);
+        program.push_literal(2);
+        program.call_func(func);
+
+        let (optimized, removed) = eliminate_dead_code(&program);
+        eprintln!("{program}\n{optimized}");
+        // Only the jump over the else branch, which follows the `Ret`, is dead.
+        assert_eq!(removed, 5);
+        assert_eq!(vm::create_and_run(&optimized), vm::create_and_run(&program));
+        assert_eq!(
+            vm::create_and_run(&optimized).unwrap(),
+            [Value::Int(10), Value::Int(2)]
+        );
+    }
+
+    #[test]
+    fn test_dead_code_keeps_loops() {
+        let program = compile_str(include_str!("../examples/while_loop.pty"));
+        let (optimized, removed) = eliminate_dead_code(&program);
+        assert_eq!(removed, 0);
+        assert_eq!(optimized, program);
+    }
+
+    #[test]
+    fn test_dead_code_removes_functions_only_called_from_dead_code() {
+        let mut program = Program::new();
+        let func = program.push_func(|func| _ = func.push_literal(1));
+        let skip = program.push_jump(0);
+        program.call_func(func);
+        program.patch_jump(skip);
+
+        let (optimized, removed) = eliminate_dead_code(&program);
+        eprintln!("{program}\n{optimized}");
+        // The call is dead, and so is the function it is the only caller of.
+        assert_eq!(removed, 12);
+        assert!(!optimized.to_string().contains("Ret"));
+        assert_eq!(vm::create_and_run(&optimized), vm::create_and_run(&program));
+    }
+
+    #[test]
+    fn test_dead_code_keeps_tail_called_functions() {
+        let mut program = Program::new();
+        let inner = program.push_func(|func| _ = func.push_literal(1));
+        let outer = program.push_func(|func| _ = func.tail_call_func(inner));
+        program.call_func(outer);
+
+        let (optimized, removed) = eliminate_dead_code(&program);
+        eprintln!("{program}\n{optimized}");
+        // Only the `Ret` after the tail call is dead.
+        assert_eq!(removed, 1);
+        assert_eq!(vm::create_and_run(&optimized).unwrap(), [Value::Int(1)]);
+    }
+
+    #[test]
+    fn test_dead_code_drops_names_of_removed_functions() {
+        let mut program = Program::new();
+        program.push_named_func("dead", |func| _ = func.push_literal(1));
+        let live = program.push_named_func("live", |func| {
+            func.push_literal(0);
+            func.push_opcode(OpCode::Mod);
+        });
+        program.push_literal(1);
+        program.call_func(live);
+
+        let (optimized, removed) = eliminate_dead_code(&program);
+        eprintln!("{program}\n{optimized}");
+        assert_eq!(removed, 6);
+        assert_eq!(
+            optimized.func_names.into_iter().collect::<Vec<_>>(),
+            [(live - removed, "live".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_call_jump_to_next_is_kept() {
+        let mut program = Program::new();
+        program.push_literal(1);
+        program.bytes.push(OpCode::PrepareFuncCall as u8);
+        program.push_jump(program.len() + 5);
+        program.push_literal(2);
+        program.push_opcode(OpCode::Ret);
+
+        let optimized = peephole(&program);
+        assert_eq!(optimized.bytes, program.bytes);
+    }
+}
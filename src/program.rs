@@ -1,11 +1,32 @@
-use crate::{builtins::Builtin, op_codes::OpCode, value::Value};
-use std::ops::Deref;
+use crate::{
+    bigint::BigInt,
+    builtins::Builtin,
+    label::{Label, Labels},
+    lines::{LineTable, Location},
+    op_codes::OpCode,
+    value::Value,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::Hash,
+    ops::{Deref, DerefMut, Range},
+    rc::Rc,
+};
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Program {
     pub bytes: Vec<u8>,
     pub constants: Vec<Value>,
     pub idents: Vec<String>,
+    constant_indices: Interner<Value>,
+    ident_indices: Interner<String>,
+    pub(crate) labels: Labels,
+    /// Where each instruction came from, if the program was assembled from
+    /// source.
+    pub lines: Option<LineTable>,
+    /// Names of functions by entry offset, for tracebacks.
+    pub func_names: BTreeMap<usize, String>,
 }
 
 impl Program {
@@ -13,11 +34,26 @@ impl Program {
     pub fn new() -> Self {
         Self::default()
     }
+    /// The source location of the instruction at `offset`.
+    #[must_use]
+    pub fn location(&self, offset: usize) -> Option<Location> {
+        self.lines.as_ref()?.location(offset)
+    }
+    /// Returns the index of `value` in `constants`, adding it if it is not
+    /// there yet.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constant_indices.intern(&mut self.constants, value)
+    }
+    /// Returns the index of `name` in `idents`, adding it if it is not there
+    /// yet.
+    pub fn add_ident(&mut self, name: impl Into<String>) -> usize {
+        self.ident_indices.intern(&mut self.idents, name.into())
+    }
     #[inline]
     pub fn load_const(&mut self, value: Value) -> usize {
         self.bytes.push(OpCode::LoadConst as u8);
 
-        let index = insert_vec(&mut self.constants, value);
+        let index = self.add_constant(value);
 
         let index_u32 = u32::try_from(index).unwrap();
         self.bytes.extend_from_slice(&index_u32.to_le_bytes());
@@ -31,8 +67,7 @@ impl Program {
     #[inline]
     pub fn store_name(&mut self, name: impl Into<String>) -> usize {
         self.bytes.push(OpCode::StoreName as u8);
-        let name = name.into();
-        let index = insert_vec(&mut self.idents, name);
+        let index = self.add_ident(name);
 
         let index_u32 = u32::try_from(index).unwrap();
         self.bytes.extend_from_slice(&index_u32.to_le_bytes());
@@ -43,8 +78,7 @@ impl Program {
     pub fn load_name(&mut self, name: impl Into<String>) -> usize {
         self.bytes.push(OpCode::LoadName as u8);
 
-        let name = name.into();
-        let index = insert_vec(&mut self.idents, name);
+        let index = self.add_ident(name);
 
         let index_u32 = u32::try_from(index).unwrap();
         self.bytes.extend_from_slice(&index_u32.to_le_bytes());
@@ -63,11 +97,28 @@ impl Program {
         self.patch_jump(jump_end);
         index
     }
+    /// Like [`Program::push_func`], but records `name` for tracebacks.
+    #[inline]
+    pub fn push_named_func<F>(&mut self, name: impl Into<String>, func: F) -> usize
+    where
+        F: FnOnce(&mut Self),
+    {
+        let index = self.push_func(func);
+        self.func_names.insert(index, name.into());
+        index
+    }
     #[inline]
     pub fn call_func(&mut self, func: usize) {
         self.bytes.push(OpCode::PrepareFuncCall as u8);
         self.push_jump(func);
     }
+    /// Calls `func` in place of returning from the current function.
+    #[inline]
+    pub fn tail_call_func(&mut self, func: usize) -> usize {
+        self.bytes.push(OpCode::TailCall as u8);
+        self.push_u32(u32::try_from(func).unwrap());
+        self.len() - 4
+    }
     #[inline]
     pub fn push_builtin(&mut self, builtin: Builtin) -> usize {
         self.bytes.push(OpCode::LoadBuiltin as u8);
@@ -86,17 +137,33 @@ impl Program {
         let bytes = val.to_le_bytes();
         self.bytes.extend_from_slice(&bytes);
     }
+    /// Pushes a jump with the opcode `op` and returns the offset of its
+    /// target, for [`Program::patch_jump`].
     #[inline]
-    pub fn push_jump(&mut self, index: usize) -> usize {
-        self.bytes.push(OpCode::Jump as u8);
+    pub(crate) fn push_jump_op(&mut self, op: OpCode, index: usize) -> usize {
+        self.bytes.push(op as u8);
         self.push_u32(u32::try_from(index).unwrap());
         self.len() - 4
     }
     #[inline]
+    pub fn push_jump(&mut self, index: usize) -> usize {
+        self.push_jump_op(OpCode::Jump, index)
+    }
+    #[inline]
     pub fn push_pop_jump_if_false(&mut self, index: usize) -> usize {
-        self.bytes.push(OpCode::PopJumpIfFalse as u8);
-        self.push_u32(u32::try_from(index).unwrap());
-        self.len() - 4
+        self.push_jump_op(OpCode::PopJumpIfFalse, index)
+    }
+    #[inline]
+    pub fn push_pop_jump_if_true(&mut self, index: usize) -> usize {
+        self.push_jump_op(OpCode::PopJumpIfTrue, index)
+    }
+    #[inline]
+    pub fn push_jump_if_false_or_pop(&mut self, index: usize) -> usize {
+        self.push_jump_op(OpCode::JumpIfFalseOrPop, index)
+    }
+    #[inline]
+    pub fn push_jump_if_true_or_pop(&mut self, index: usize) -> usize {
+        self.push_jump_op(OpCode::JumpIfTrueOrPop, index)
     }
     #[inline]
     pub fn patch_jump(&mut self, jump: usize) {
@@ -128,18 +195,82 @@ impl Program {
         self.patch_jump(jump_else);
     }
 
+    /// Pushes `lhs and rhs`: `rhs` is only evaluated if `lhs` is truthy, and
+    /// the value of whichever was evaluated last is left on the stack.
     #[inline]
-    pub fn push_while_loop<F1, F2>(&mut self, condition: F1, body: F2)
+    pub fn push_and<F1, F2>(&mut self, lhs: F1, rhs: F2)
+    where
+        F1: FnOnce(&mut Self),
+        F2: FnOnce(&mut Self),
+    {
+        lhs(self);
+        let jump = self.push_jump_if_false_or_pop(0);
+        rhs(self);
+        self.patch_jump(jump);
+    }
+
+    /// Pushes `lhs or rhs`: `rhs` is only evaluated if `lhs` is falsy.
+    #[inline]
+    pub fn push_or<F1, F2>(&mut self, lhs: F1, rhs: F2)
     where
         F1: FnOnce(&mut Self),
         F2: FnOnce(&mut Self),
     {
-        let start = self.len();
+        lhs(self);
+        let jump = self.push_jump_if_true_or_pop(0);
+        rhs(self);
+        self.patch_jump(jump);
+    }
+
+    #[inline]
+    pub fn push_while_loop<F1, F2>(&mut self, condition: F1, body: F2)
+    where
+        F1: FnOnce(&mut Self),
+        F2: FnOnce(&mut LoopBody),
+    {
+        let start = self.new_label();
+        let end = self.new_label();
+        self.bind(start);
         condition(self);
-        let end = self.push_pop_jump_if_false(0);
-        body(self);
-        self.push_jump(start);
-        self.patch_jump(end);
+        self.pop_jump_if_false_to(end);
+        body(&mut LoopBody {
+            program: self,
+            break_to: end,
+            continue_to: start,
+        });
+        self.jump_to(start);
+        self.bind(end);
+    }
+
+    /// Pushes a loop that runs `body` with the variable `name` set to each
+    /// value of `range` in turn.
+    #[inline]
+    pub fn push_for_range<F>(&mut self, name: &str, range: Range<i64>, body: F)
+    where
+        F: FnOnce(&mut LoopBody),
+    {
+        let start = self.new_label();
+        let next = self.new_label();
+        let end = self.new_label();
+        self.push_literal(range.start);
+        self.store_name(name);
+        self.bind(start);
+        self.load_name(name);
+        self.push_literal(range.end);
+        self.push_opcode(OpCode::Lt);
+        self.pop_jump_if_false_to(end);
+        body(&mut LoopBody {
+            program: self,
+            break_to: end,
+            continue_to: next,
+        });
+        self.bind(next);
+        self.load_name(name);
+        self.push_literal(1);
+        self.push_opcode(OpCode::Add);
+        self.store_name(name);
+        self.jump_to(start);
+        self.bind(end);
     }
 
     #[must_use]
@@ -150,6 +281,48 @@ impl Program {
     }
 }
 
+/// The program a loop body is pushed to, which can also jump out of the loop
+/// or to its next iteration.
+pub struct LoopBody<'a> {
+    program: &'a mut Program,
+    break_to: Label,
+    continue_to: Label,
+}
+
+impl LoopBody<'_> {
+    /// Jumps past the end of the loop.
+    pub fn break_(&mut self) {
+        self.program.jump_to(self.break_to);
+    }
+    /// Jumps to the next iteration of the loop.
+    pub fn continue_(&mut self) {
+        self.program.jump_to(self.continue_to);
+    }
+    /// The label `break_` jumps to, for leaving this loop from a nested one.
+    #[must_use]
+    pub fn break_label(&self) -> Label {
+        self.break_to
+    }
+    /// The label `continue_` jumps to.
+    #[must_use]
+    pub fn continue_label(&self) -> Label {
+        self.continue_to
+    }
+}
+
+impl Deref for LoopBody<'_> {
+    type Target = Program;
+    fn deref(&self) -> &Program {
+        self.program
+    }
+}
+
+impl DerefMut for LoopBody<'_> {
+    fn deref_mut(&mut self) -> &mut Program {
+        self.program
+    }
+}
+
 impl Deref for Program {
     type Target = Vec<u8>;
     fn deref(&self) -> &Self::Target {
@@ -157,9 +330,120 @@ impl Deref for Program {
     }
 }
 
-fn insert_vec<T: PartialOrd>(vec: &mut Vec<T>, value: T) -> usize {
-    vec.iter().position(|val| val == &value).unwrap_or_else(|| {
+/// A value that can be looked up in an [`Interner`] by `Key`.
+pub(crate) trait Intern {
+    type Key: Hash + Eq;
+    fn key(&self) -> Self::Key;
+}
+
+/// Hashable identity of a constant. Floats are compared by bit pattern, so
+/// `NaN` is deduplicated and `-0.0` is kept apart from `0.0`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum ConstantKey {
+    Int(i64),
+    BigInt(BigInt),
+    Float(u64),
+    Str(Rc<String>),
+}
+
+impl Intern for Value {
+    type Key = ConstantKey;
+    fn key(&self) -> ConstantKey {
+        match self {
+            Value::Int(int) => ConstantKey::Int(*int),
+            Value::BigInt(int) => ConstantKey::BigInt(int.clone()),
+            Value::Float(float) => ConstantKey::Float(float.to_bits()),
+            Value::Str(str) => ConstantKey::Str(str.clone()),
+        }
+    }
+}
+
+impl Intern for String {
+    type Key = String;
+    fn key(&self) -> String {
+        self.clone()
+    }
+}
+
+/// Maps the values of a `Vec` to their indices so they can be deduplicated
+/// in O(1).
+///
+/// The vector is public and may be changed behind the interner's back, so
+/// values appended since the last call are indexed first and a stale hit
+/// causes a rebuild. The interner is a cache: it is ignored by `==` and
+/// `Debug`.
+pub(crate) struct Interner<T: Intern> {
+    indices: HashMap<T::Key, usize>,
+    len: usize,
+}
+
+impl<T: Intern> Interner<T> {
+    pub(crate) fn intern(&mut self, vec: &mut Vec<T>, value: T) -> usize {
+        let key = value.key();
+        if self.len > vec.len() {
+            self.rebuild(vec);
+        }
+        self.index(vec);
+        if let Some(&index) = self.indices.get(&key) {
+            if vec[index].key() == key {
+                return index;
+            }
+            self.rebuild(vec);
+            if let Some(&index) = self.indices.get(&key) {
+                return index;
+            }
+        }
         vec.push(value);
+        self.indices.insert(key, vec.len() - 1);
+        self.len = vec.len();
         vec.len() - 1
-    })
+    }
+
+    fn rebuild(&mut self, vec: &[T]) {
+        self.indices.clear();
+        self.len = 0;
+        self.index(vec);
+    }
+
+    /// Indexes the values added to `vec` since the last call, keeping the
+    /// first index of duplicates.
+    fn index(&mut self, vec: &[T]) {
+        for (index, value) in vec.iter().enumerate().skip(self.len) {
+            self.indices.entry(value.key()).or_insert(index);
+        }
+        self.len = vec.len();
+    }
+}
+
+impl<T: Intern> Default for Interner<T> {
+    fn default() -> Self {
+        Self {
+            indices: HashMap::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T: Intern> Clone for Interner<T>
+where
+    T::Key: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            indices: self.indices.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<T: Intern> PartialEq for Interner<T> {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl<T: Intern> fmt::Debug for Interner<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interner").finish_non_exhaustive()
+    }
 }
@@ -0,0 +1,815 @@
+//! An experimental register-based execution engine.
+//!
+//! A verified program is translated so that every stack slot of a function
+//! becomes a register in that function's frame. The verifier knows the depth
+//! before every instruction, so `Add` at depth 3 becomes "add registers 1 and
+//! 2 into 1", and `Dup`, `Swap` and `Pop` become register moves or nothing.
+//! Constants are only moved into a register when something other than an
+//! operator needs them there, so `1 +` reads the constant directly.
+//!
+//! Frames overlap: a function's frame starts at the lowest argument it pops,
+//! and the values it leaves on return are already where the caller expects
+//! them. A tail call starts the callee's frame the same way but returns to
+//! the current caller. A block of code reached from two functions is
+//! translated once for each.
+
+use std::{cmp::Ordering, mem};
+
+use crate::{
+    binops::{binary_op, unary_op, BinOpResult},
+    builtins::Builtin,
+    cfg::Cfg,
+    instruction::Instruction,
+    op_codes::OpCode,
+    program::Program,
+    traceback,
+    value::Value,
+    verify::{verify, StackInfo, VerifyError},
+    vm::{exit, VmError, NO_CALLER},
+};
+
+/// Where an instruction reads a value from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    /// A register of the current frame.
+    Reg(u32),
+    Const(u32),
+}
+
+/// A register instruction. Registers are relative to the base of the current
+/// frame and jump targets are indices into the translated instructions.
+///
+/// Reading a register through an [`Operand`] moves the value out of it, which
+/// is safe because a slot is never read again after it is popped.
+#[derive(Clone, Copy)]
+enum Op {
+    Move {
+        dst: u32,
+        src: Operand,
+    },
+    Copy {
+        dst: u32,
+        src: u32,
+    },
+    Swap(u32, u32),
+    Binary {
+        func: fn(Value, Value) -> BinOpResult,
+        dst: u32,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Unary {
+        func: fn(Value) -> BinOpResult,
+        dst: u32,
+        src: Operand,
+    },
+    StoreName {
+        name: u32,
+        src: Operand,
+    },
+    LoadName {
+        dst: u32,
+        name: u32,
+    },
+    IncName {
+        name: u32,
+        index: u32,
+    },
+    Print(Operand),
+    /// Exits with the value in the register below `top`, if there is one.
+    Exit {
+        top: u32,
+    },
+    Jump(u32),
+    /// Jumps if the truthiness of `cond` is `when`.
+    JumpIf {
+        cond: Operand,
+        when: bool,
+        target: u32,
+    },
+    /// Like `JumpIf`, but leaves the value in its register.
+    JumpIfKeep {
+        reg: u32,
+        when: bool,
+        target: u32,
+    },
+    JumpIfNotLtConst {
+        reg: u32,
+        index: u32,
+        target: u32,
+    },
+    /// Calls the function at `target`, whose frame starts at register
+    /// `frame` and needs `size` registers.
+    Call {
+        target: u32,
+        frame: u32,
+        size: u32,
+        /// The callee's entry offset, for tracebacks.
+        entry: u32,
+        /// Whether the callee returns to the caller of the current function
+        /// instead.
+        tail: bool,
+    },
+    Ret,
+    /// Ends the program, leaving the registers below `top` as the stack.
+    Halt {
+        top: u32,
+    },
+}
+
+/// A verified program translated to register instructions.
+pub struct Compiled<'a> {
+    program: &'a Program,
+    ops: Vec<Op>,
+    /// The offset of the instruction each op was translated from.
+    offsets: Vec<usize>,
+    /// Registers used by the top level.
+    size: usize,
+}
+
+/// Verifies and runs `program` with the register engine, returning the
+/// remaining stack.
+///
+/// # Errors
+/// Fails if the program does not verify or raises a runtime error.
+pub fn create_and_run(program: &Program) -> Result<Vec<Value>, VmError> {
+    Compiled::new(program)?.run()
+}
+
+impl<'a> Compiled<'a> {
+    /// # Errors
+    /// Fails if the program does not verify.
+    pub fn new(program: &'a Program) -> Result<Self, VerifyError> {
+        let info = verify(program)?;
+        let mut translator = Translator::new(&info);
+        translator.translate();
+        Ok(Self {
+            program,
+            ops: translator.ops,
+            offsets: translator.offsets,
+            size: translator.frames[0].size as usize,
+        })
+    }
+
+    /// Runs the program from the start, returning the remaining stack.
+    ///
+    /// # Errors
+    /// Fails if the program raises a runtime error.
+    pub fn run(&self) -> Result<Vec<Value>, VmError> {
+        let constants = &self.program.constants;
+        let mut regs = vec![Value::Int(0); self.size];
+        let mut variables: Vec<Option<Value>> = vec![None; self.program.idents.len()];
+        // The return op, the caller's base and the callee's entry offset.
+        let mut call_stack: Vec<(usize, usize, usize)> = vec![];
+        let mut base = 0;
+        let mut pc = 0;
+
+        macro_rules! reg {
+            ($reg:expr) => {
+                regs[base + $reg as usize]
+            };
+        }
+        macro_rules! read {
+            ($operand:expr) => {
+                match $operand {
+                    Operand::Reg(reg) => mem::replace(&mut reg!(reg), Value::Int(0)),
+                    Operand::Const(index) => constants[index as usize].clone(),
+                }
+            };
+        }
+
+        // As in `Decoded::run`, `pc` is read after an error to locate it.
+        let result = (|| loop {
+            let op = self.ops[pc];
+            pc += 1;
+            match op {
+                Op::Move { dst, src } => reg!(dst) = read!(src),
+                Op::Copy { dst, src } => reg!(dst) = reg!(src).clone(),
+                Op::Swap(a, b) => regs.swap(base + a as usize, base + b as usize),
+                Op::Binary {
+                    func,
+                    dst,
+                    lhs,
+                    rhs,
+                } => reg!(dst) = func(read!(lhs), read!(rhs))?,
+                Op::Unary { func, dst, src } => reg!(dst) = func(read!(src))?,
+                Op::StoreName { name, src } => variables[name as usize] = Some(read!(src)),
+                Op::LoadName { dst, name } => {
+                    let Some(val) = &variables[name as usize] else {
+                        let name = self.program.idents[name as usize].clone();
+                        return Err(VmError::UndefinedVariable(name));
+                    };
+                    reg!(dst) = val.clone();
+                }
+                Op::IncName { name, index } => {
+                    let Some(val) = variables[name as usize].as_mut() else {
+                        let name = self.program.idents[name as usize].clone();
+                        return Err(VmError::UndefinedVariable(name));
+                    };
+                    val.add_in_place(constants[index as usize].clone())?;
+                }
+                Op::Print(src) => println!("{}", read!(src)),
+                Op::Exit { top } => {
+                    let depth = base + top as usize;
+                    exit(depth.checked_sub(1).map(|top| regs[top].clone()));
+                }
+                Op::Jump(target) => pc = target as usize,
+                Op::JumpIf { cond, when, target } => {
+                    if bool::from(&read!(cond)) == when {
+                        pc = target as usize;
+                    }
+                }
+                Op::JumpIfKeep { reg, when, target } if bool::from(&reg!(reg)) == when => {
+                    pc = target as usize;
+                }
+                Op::JumpIfKeep { .. } => (),
+                Op::JumpIfNotLtConst { reg, index, target } => {
+                    if !reg!(reg)
+                        .compare(&constants[index as usize])?
+                        .is_some_and(Ordering::is_lt)
+                    {
+                        pc = target as usize;
+                    }
+                }
+                Op::Call {
+                    target,
+                    frame,
+                    size,
+                    entry,
+                    tail,
+                } => {
+                    match call_stack.last_mut() {
+                        _ if !tail => call_stack.push((pc, base, entry as usize)),
+                        Some(call) => call.2 = entry as usize,
+                        None => (),
+                    }
+                    base += frame as usize;
+                    if regs.len() < base + size as usize {
+                        regs.resize(base + size as usize, Value::Int(0));
+                    }
+                    pc = target as usize;
+                }
+                Op::Ret => (pc, base, _) = call_stack.pop().expect(NO_CALLER),
+                Op::Halt { top } => {
+                    regs.truncate(base + top as usize);
+                    return Ok(regs);
+                }
+            }
+        })();
+        result.map_err(|err| err.at(self.traceback(&call_stack, pc - 1)))
+    }
+
+    /// The frames active when the op at `failed` raised an error, given the
+    /// return op, base and entry offset of each call.
+    fn traceback(
+        &self,
+        call_stack: &[(usize, usize, usize)],
+        failed: usize,
+    ) -> Vec<traceback::Frame> {
+        // Each call returns to the op after its `Call`, which was translated
+        // from a 5-byte `Jump`.
+        let calls = call_stack
+            .iter()
+            .map(|&(ret, _, entry)| (entry, self.offsets[ret - 1] + 5));
+        traceback::capture(self.program, calls, self.offsets[failed])
+    }
+}
+
+/// The registers a function needs, relative to the lowest argument it pops.
+struct Frame {
+    args: isize,
+    size: u32,
+}
+
+/// Where a jump lands, before the instructions are laid out.
+#[derive(Clone, Copy)]
+enum Target {
+    Block {
+        func: usize,
+        block: usize,
+    },
+    /// The end of the program, with `top` registers in use.
+    End {
+        top: u32,
+    },
+}
+
+struct Translator<'a> {
+    cfg: &'a Cfg,
+    info: &'a StackInfo,
+    frames: Vec<Frame>,
+    ops: Vec<Op>,
+    /// The offset of the instruction each op was translated from.
+    offsets: Vec<usize>,
+    /// The offset of the instruction being translated.
+    offset: usize,
+    /// The index in `ops` of each block, for each function.
+    starts: Vec<Vec<Option<u32>>>,
+    /// Jumps whose targets are filled in once every block is laid out.
+    fixups: Vec<(usize, Target)>,
+    /// The operand for each stack slot of the block being translated. A slot
+    /// is either in its own register or a constant that has not been moved
+    /// there yet.
+    slots: Vec<Operand>,
+}
+
+impl<'a> Translator<'a> {
+    #[allow(clippy::cast_possible_wrap)]
+    fn new(info: &'a StackInfo) -> Self {
+        let cfg = &info.cfg;
+        let frames = info
+            .functions
+            .iter()
+            .zip(&info.depths)
+            .map(|(func, depths)| {
+                let args = func.args as isize;
+                let max = cfg
+                    .instructions
+                    .iter()
+                    .zip(depths)
+                    .filter_map(|(&(_, inst), depth)| {
+                        let effect = inst.stack_effect();
+                        depth.map(|depth| depth - effect.pops as isize + effect.pushes as isize)
+                    })
+                    .fold(0, isize::max);
+                Frame {
+                    args,
+                    size: u32::try_from(args + max).unwrap(),
+                }
+            })
+            .collect();
+        let functions = info.functions.len();
+        Self {
+            cfg,
+            info,
+            frames,
+            ops: vec![],
+            offsets: vec![],
+            offset: 0,
+            starts: vec![vec![None; cfg.blocks.len()]; functions],
+            fixups: vec![],
+            slots: vec![],
+        }
+    }
+
+    fn translate(&mut self) {
+        for func in 0..self.info.functions.len() {
+            let reached: Vec<usize> = (0..self.cfg.blocks.len())
+                .filter(|&b| self.depth(func, self.cfg.blocks[b].start).is_some())
+                .collect();
+            for (pos, &b) in reached.iter().enumerate() {
+                self.starts[func][b] = Some(self.next_op());
+                let next = reached.get(pos + 1).copied();
+                self.translate_block(func, b, next);
+            }
+        }
+        if self.cfg.blocks.is_empty() {
+            self.emit(Op::Halt { top: 0 });
+        }
+
+        for (op, target) in mem::take(&mut self.fixups) {
+            let index = match target {
+                Target::Block { func, block } => self.starts[func][block].unwrap(),
+                Target::End { top } => {
+                    self.emit(Op::Halt { top });
+                    self.next_op() - 1
+                }
+            };
+            match &mut self.ops[op] {
+                Op::Jump(target)
+                | Op::JumpIf { target, .. }
+                | Op::JumpIfKeep { target, .. }
+                | Op::JumpIfNotLtConst { target, .. }
+                | Op::Call { target, .. } => *target = index,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// The depth before the `i`th instruction, counted in registers from the
+    /// base of the frame.
+    fn depth(&self, func: usize, i: usize) -> Option<u32> {
+        let depth = self.info.depths[func][i]? + self.frames[func].args;
+        Some(u32::try_from(depth).unwrap())
+    }
+
+    /// Translates block `b` of `func`, where `next` is the block laid out
+    /// after it.
+    fn translate_block(&mut self, func: usize, b: usize, next: Option<usize>) {
+        let block = &self.cfg.blocks[b];
+        let depth = self.depth(func, block.start).unwrap();
+        self.slots = (0..depth).map(Operand::Reg).collect();
+
+        for i in block.start..block.end {
+            let (offset, inst) = self.cfg.instructions[i];
+            self.offset = offset;
+            let is_call = i > 0 && self.cfg.instructions[i - 1].1 == Instruction::PrepareFuncCall;
+            // The registers in use if execution continues with the next
+            // instruction.
+            let mut fallthrough = None;
+            match inst {
+                Instruction::Nop | Instruction::PrepareFuncCall => (),
+                Instruction::Dup
+                | Instruction::Pop
+                | Instruction::Swap
+                | Instruction::DupSwap
+                | Instruction::Rot
+                | Instruction::Over
+                | Instruction::Pick(_)
+                | Instruction::Roll(_)
+                | Instruction::PopN(_) => self.shuffle(inst),
+                Instruction::LoadConst(index) => self.slots.push(Operand::Const(index)),
+                Instruction::StoreName(name) => {
+                    let src = self.slots.pop().unwrap();
+                    self.emit(Op::StoreName { name, src });
+                }
+                Instruction::LoadName(name) => {
+                    let dst = self.top();
+                    self.emit(Op::LoadName { dst, name });
+                    self.slots.push(Operand::Reg(dst));
+                }
+                Instruction::IncName(name, index) => _ = self.emit(Op::IncName { name, index }),
+                Instruction::AddConst(index) => {
+                    self.slots.push(Operand::Const(index));
+                    self.binary(binary_op(OpCode::Add).unwrap());
+                }
+                Instruction::LoadBuiltin(Builtin::Print) => {
+                    let src = self.slots.pop().unwrap();
+                    self.emit(Op::Print(src));
+                }
+                Instruction::LoadBuiltin(Builtin::Exit) => {
+                    self.materialize();
+                    let top = self.top();
+                    self.emit(Op::Exit { top });
+                }
+                Instruction::Ret => {
+                    self.materialize();
+                    self.emit(Op::Ret);
+                }
+                Instruction::Jump(target) if is_call => {
+                    fallthrough = self.call(target as usize, false);
+                }
+                Instruction::TailCall(target) => _ = self.call(target as usize, true),
+                Instruction::Jump(target) => {
+                    self.materialize();
+                    self.jump(func, target as usize);
+                }
+                Instruction::PopJumpIfFalse(_)
+                | Instruction::PopJumpIfTrue(_)
+                | Instruction::JumpIfFalseOrPop(_)
+                | Instruction::JumpIfTrueOrPop(_)
+                | Instruction::JumpIfNotLtConst(..) => self.branch(func, inst),
+                _ => self.operator(inst.op_code()),
+            }
+            if !matches!(
+                inst,
+                Instruction::Jump(_) | Instruction::Ret | Instruction::TailCall(_)
+            ) {
+                fallthrough = Some(self.top());
+            }
+            if i + 1 < block.end {
+                continue;
+            }
+            let Some(top) = fallthrough else { break };
+            self.materialize();
+            let next_offset = offset + inst.size();
+            match self.cfg.block_at(next_offset) {
+                None => _ = self.emit(Op::Halt { top }),
+                Some(b) if Some(b) != next => self.jump(func, next_offset),
+                Some(_) => (),
+            }
+        }
+    }
+
+    fn next_op(&self) -> u32 {
+        u32::try_from(self.ops.len()).unwrap()
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.ops.push(op);
+        self.offsets.push(self.offset);
+        self.ops.len() - 1
+    }
+
+    /// The register above the top of the stack.
+    fn top(&self) -> u32 {
+        u32::try_from(self.slots.len()).unwrap()
+    }
+
+    /// Moves every pending constant into its register, as jumps and calls
+    /// expect the whole stack to be in registers.
+    fn materialize(&mut self) {
+        for reg in 0..self.top() {
+            let src = self.slots[reg as usize];
+            if src != Operand::Reg(reg) {
+                self.emit(Op::Move { dst: reg, src });
+                self.slots[reg as usize] = Operand::Reg(reg);
+            }
+        }
+    }
+
+    /// Translates a conditional jump.
+    fn branch(&mut self, func: usize, inst: Instruction) {
+        let offset = inst.jump_target().unwrap() as usize;
+        let op = match inst {
+            Instruction::PopJumpIfFalse(_) | Instruction::PopJumpIfTrue(_) => {
+                let cond = self.slots.pop().unwrap();
+                self.materialize();
+                let when = matches!(inst, Instruction::PopJumpIfTrue(_));
+                Op::JumpIf {
+                    cond,
+                    when,
+                    target: 0,
+                }
+            }
+            Instruction::JumpIfFalseOrPop(_) | Instruction::JumpIfTrueOrPop(_) => {
+                self.materialize();
+                let when = matches!(inst, Instruction::JumpIfTrueOrPop(_));
+                Op::JumpIfKeep {
+                    reg: self.top() - 1,
+                    when,
+                    target: 0,
+                }
+            }
+            Instruction::JumpIfNotLtConst(index, _) => {
+                self.materialize();
+                Op::JumpIfNotLtConst {
+                    reg: self.top() - 1,
+                    index,
+                    target: 0,
+                }
+            }
+            _ => unreachable!("{inst:?}"),
+        };
+        let op = self.emit(op);
+        self.fixups.push((op, self.target(func, offset)));
+        if let Op::JumpIfKeep { .. } = self.ops[op] {
+            // Falling through pops the value the jump keeps.
+            self.slots.pop();
+        }
+    }
+
+    /// Translates an instruction that only rearranges the stack.
+    fn shuffle(&mut self, inst: Instruction) {
+        match inst {
+            Instruction::Dup => self.pick(0),
+            Instruction::Pop => self.slots.truncate(self.slots.len() - 1),
+            Instruction::Swap => self.roll(1),
+            Instruction::DupSwap => {
+                self.pick(0);
+                let len = self.slots.len();
+                self.swap_slots(len - 2, len - 3);
+            }
+            Instruction::Rot => self.roll(2),
+            Instruction::Over => self.pick(1),
+            Instruction::Pick(n) => self.pick(n),
+            Instruction::Roll(n) => self.roll(n),
+            Instruction::PopN(n) => self.slots.truncate(self.slots.len() - n as usize),
+            _ => unreachable!("{inst:?}"),
+        }
+    }
+
+    /// Copies the slot `n` below the top onto the top.
+    fn pick(&mut self, n: u32) {
+        let dst = self.top();
+        match self.slots[self.slots.len() - 1 - n as usize] {
+            Operand::Const(index) => self.slots.push(Operand::Const(index)),
+            Operand::Reg(src) => {
+                self.emit(Op::Copy { dst, src });
+                self.slots.push(Operand::Reg(dst));
+            }
+        }
+    }
+
+    /// Moves the slot `n` below the top to the top, one swap at a time.
+    fn roll(&mut self, n: u32) {
+        let len = self.slots.len();
+        for slot in len - 1 - n as usize..len - 1 {
+            self.swap_slots(slot, slot + 1);
+        }
+    }
+
+    /// Swaps two stack slots. Pending constants are swapped without touching
+    /// the registers.
+    fn swap_slots(&mut self, a: usize, b: usize) {
+        let reg = |slot: usize| u32::try_from(slot).unwrap();
+        match (self.slots[a], self.slots[b]) {
+            (Operand::Const(_), Operand::Const(_)) => {}
+            (Operand::Reg(_), Operand::Reg(_)) => {
+                self.emit(Op::Swap(reg(a), reg(b)));
+                return;
+            }
+            (Operand::Reg(src), Operand::Const(_)) => {
+                self.emit(Op::Move {
+                    dst: reg(b),
+                    src: Operand::Reg(src),
+                });
+                self.slots[a] = Operand::Reg(reg(b));
+            }
+            (Operand::Const(_), Operand::Reg(src)) => {
+                self.emit(Op::Move {
+                    dst: reg(a),
+                    src: Operand::Reg(src),
+                });
+                self.slots[b] = Operand::Reg(reg(a));
+            }
+        }
+        self.slots.swap(a, b);
+    }
+
+    fn operator(&mut self, op: OpCode) {
+        if let Some(func) = binary_op(op) {
+            self.binary(func);
+        } else {
+            let src = self.slots.pop().unwrap();
+            let dst = self.top();
+            let func = unary_op(op).unwrap();
+            self.emit(Op::Unary { func, dst, src });
+            self.slots.push(Operand::Reg(dst));
+        }
+    }
+
+    fn binary(&mut self, func: fn(Value, Value) -> BinOpResult) {
+        let rhs = self.slots.pop().unwrap();
+        let lhs = self.slots.pop().unwrap();
+        let dst = self.top();
+        self.emit(Op::Binary {
+            func,
+            dst,
+            lhs,
+            rhs,
+        });
+        self.slots.push(Operand::Reg(dst));
+    }
+
+    fn target(&self, func: usize, offset: usize) -> Target {
+        match self.cfg.block_at(offset) {
+            Some(block) => Target::Block { func, block },
+            None => Target::End { top: self.top() },
+        }
+    }
+
+    /// Emits a jump, or a `Halt` if it jumps to the end of the program.
+    fn jump(&mut self, func: usize, offset: usize) {
+        match self.target(func, offset) {
+            Target::End { top } => _ = self.emit(Op::Halt { top }),
+            target @ Target::Block { .. } => {
+                let op = self.emit(Op::Jump(0));
+                self.fixups.push((op, target));
+            }
+        }
+    }
+
+    /// Emits a call to the function entered at `offset`, returning the
+    /// registers in use once it returns, or `None` if it never does.
+    fn call(&mut self, offset: usize, tail: bool) -> Option<u32> {
+        self.materialize();
+        let top = self.top();
+        let Some(block) = self.cfg.block_at(offset) else {
+            // Calling the end of the program ends it.
+            self.emit(Op::Halt { top });
+            return None;
+        };
+        let callee = self
+            .info
+            .functions
+            .iter()
+            .position(|callee| callee.entry == offset)
+            .unwrap();
+        let frame = top - u32::try_from(self.frames[callee].args).unwrap();
+        let size = self.frames[callee].size;
+        let entry = u32::try_from(offset).unwrap();
+        let op = self.emit(Op::Call {
+            target: 0,
+            frame,
+            size,
+            entry,
+            tail,
+        });
+        self.fixups.push((
+            op,
+            Target::Block {
+                func: callee,
+                block,
+            },
+        ));
+        let net = self.info.functions[callee].net?;
+        Some(u32::try_from(isize::try_from(top).unwrap() + net).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::compile_str, optimize::fuse_instructions, vm};
+
+    /// Runs `program` with both engines, which must agree.
+    fn run_both(program: &Program) -> Result<Vec<Value>, VmError> {
+        let stack = vm::create_and_run(program);
+        assert_eq!(create_and_run(program), stack);
+        stack
+    }
+
+    #[test]
+    fn test_stack_shuffles() {
+        let program = compile_str("1 2 swap 3 dup_swap 4 swap dup + - * swap");
+        assert_eq!(
+            run_both(&program),
+            Ok(vec![Value::Int(2), Value::Int(-2), Value::Int(3)])
+        );
+        let program = compile_str("0 1 ?skip 1 2 swap dup_swap - * - @skip 7 swap");
+        assert_eq!(run_both(&program), Ok(vec![Value::Int(7), Value::Int(-1)]));
+    }
+
+    #[test]
+    fn test_loops() {
+        let program = compile_str(include_str!("../examples/while_loop.pty"));
+        assert_eq!(
+            run_both(&program),
+            Ok(vec![Value::Int(5040), Value::Int(7)])
+        );
+        assert_eq!(run_both(&fuse_instructions(&program)), run_both(&program));
+
+        let program = compile_str("0 \"\" @start swap dup 3 < ?end 1 + swap \"ab\" + $start @end");
+        assert_eq!(
+            run_both(&program),
+            Ok(vec![Value::from("ababab"), Value::Int(3)])
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut program = Program::new();
+        let entry = program.len() + 5;
+        let fib = program.push_func(|func| {
+            func.push_opcode(OpCode::Dup);
+            func.push_literal(2);
+            func.push_opcode(OpCode::Lt);
+            func.push_if_or_else(
+                |_| {},
+                |orelse| {
+                    orelse.push_opcode(OpCode::Dup);
+                    orelse.push_literal(1);
+                    orelse.push_opcode(OpCode::Sub);
+                    orelse.call_func(entry);
+                    orelse.push_opcode(OpCode::Swap);
+                    orelse.push_literal(2);
+                    orelse.push_opcode(OpCode::Sub);
+                    orelse.call_func(entry);
+                    orelse.push_opcode(OpCode::Add);
+                },
+            );
+        });
+        // Pops two values and pushes three.
+        let spread = program.push_func(|func| {
+            func.push_opcode(OpCode::Add);
+            func.push_literal(10);
+            func.push_opcode(OpCode::DupSwap);
+        });
+        program.push_literal("below");
+        program.push_literal(15);
+        program.call_func(fib);
+        program.push_literal(1);
+        program.push_literal(2);
+        program.call_func(spread);
+        assert_eq!(
+            run_both(&program),
+            Ok(vec![
+                Value::from("below"),
+                Value::Int(610),
+                Value::Int(10),
+                Value::Int(3),
+                Value::Int(10),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_end_of_program_in_function() {
+        let mut program = Program::new();
+        let mut jump = 0;
+        let func = program.push_func(|func| {
+            func.push_literal(2);
+            jump = func.push_jump(0);
+        });
+        program.push_literal(1);
+        program.call_func(func);
+        program.push_literal("unreached");
+        program.patch_jump(jump);
+        assert_eq!(run_both(&program), Ok(vec![Value::Int(1), Value::Int(2)]));
+
+        let mut program = Program::new();
+        program.push_literal(1);
+        program.push_opcode(OpCode::PrepareFuncCall);
+        program.push_jump(program.len() + 5);
+        assert_eq!(run_both(&program), Ok(vec![Value::Int(1)]));
+    }
+
+    #[test]
+    fn test_errors() {
+        let program = compile_str("1 \"a\" <");
+        assert!(run_both(&program).is_err());
+        let program = fuse_instructions(&compile_str("\"a\" @start dup 1 < ?end $start @end"));
+        assert!(run_both(&program).is_err());
+    }
+}
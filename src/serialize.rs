@@ -0,0 +1,298 @@
+use std::fmt;
+
+use crate::{
+    bigint::BigInt,
+    lines::{LineTable, Location},
+    program::Program,
+    value::Value,
+};
+
+const MAGIC: &[u8; 4] = b"PTYB";
+const VERSION: u8 = 1;
+
+const TAG_INT: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_STR: u8 = 2;
+const TAG_BIGINT: u8 = 3;
+
+/// Optional sections follow the bytecode, each starting with its tag.
+const SECTION_LINES: u8 = 0;
+const SECTION_FUNC_NAMES: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeserializeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedEof,
+    InvalidConstantTag(u8),
+    InvalidUtf8,
+    InvalidSectionTag(u8),
+    DuplicateConstant(usize),
+    DuplicateIdent(usize),
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a pettyscript bytecode file"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported version {version}"),
+            Self::UnexpectedEof => write!(f, "unexpected end of file"),
+            Self::InvalidConstantTag(tag) => write!(f, "invalid constant tag {tag}"),
+            Self::InvalidUtf8 => write!(f, "invalid utf-8 in string"),
+            Self::InvalidSectionTag(tag) => write!(f, "invalid section tag {tag}"),
+            Self::DuplicateConstant(index) => write!(f, "duplicate constant {index}"),
+            Self::DuplicateIdent(index) => write!(f, "duplicate identifier {index}"),
+        }
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+impl Program {
+    /// Encodes the program as a header followed by the constant pool, the
+    /// identifiers and the bytecode, then the line table and function names
+    /// if there are any.
+    /// All integers are little-endian.
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+
+        write_len(&mut out, self.constants.len());
+        for constant in &self.constants {
+            match constant {
+                Value::Int(int) => {
+                    out.push(TAG_INT);
+                    out.extend_from_slice(&int.to_le_bytes());
+                }
+                Value::Float(float) => {
+                    out.push(TAG_FLOAT);
+                    out.extend_from_slice(&float.to_bits().to_le_bytes());
+                }
+                Value::Str(str) => {
+                    out.push(TAG_STR);
+                    write_str(&mut out, str);
+                }
+                Value::BigInt(int) => {
+                    out.push(TAG_BIGINT);
+                    out.push(u8::from(int.is_negative()));
+                    write_len(&mut out, int.limbs().len());
+                    for limb in int.limbs() {
+                        out.extend_from_slice(&limb.to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        write_len(&mut out, self.idents.len());
+        for ident in &self.idents {
+            write_str(&mut out, ident);
+        }
+
+        write_len(&mut out, self.bytes.len());
+        out.extend_from_slice(&self.bytes);
+
+        if let Some(lines) = &self.lines {
+            out.push(SECTION_LINES);
+            write_len(&mut out, lines.entries().len());
+            for &(offset, location) in lines.entries() {
+                write_len(&mut out, offset);
+                out.extend_from_slice(&location.line.to_le_bytes());
+                out.extend_from_slice(&location.column.to_le_bytes());
+            }
+        }
+        if !self.func_names.is_empty() {
+            out.push(SECTION_FUNC_NAMES);
+            write_len(&mut out, self.func_names.len());
+            for (&entry, name) in &self.func_names {
+                write_len(&mut out, entry);
+                write_str(&mut out, name);
+            }
+        }
+        out
+    }
+
+    /// # Errors
+    /// Fails if `bytes` was not produced by [`Program::serialize`].
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        let mut reader = Reader { bytes, head: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(DeserializeError::BadMagic);
+        }
+        let [version] = reader.read_arr()?;
+        if version != VERSION {
+            return Err(DeserializeError::UnsupportedVersion(version));
+        }
+
+        // The pools are interned, so an entry that is already there would
+        // shift the indices of the ones after it.
+        let mut program = Program::new();
+        for index in 0..reader.read_u32()? as usize {
+            let [tag] = reader.read_arr()?;
+            let constant = match tag {
+                TAG_INT => Value::Int(i64::from_le_bytes(reader.read_arr()?)),
+                TAG_FLOAT => Value::Float(f64::from_bits(u64::from_le_bytes(reader.read_arr()?))),
+                TAG_STR => Value::from(reader.read_str()?.to_owned()),
+                TAG_BIGINT => {
+                    let [negative] = reader.read_arr()?;
+                    let limbs = (0..reader.read_u32()?)
+                        .map(|_| reader.read_u32())
+                        .collect::<Result<_, _>>()?;
+                    Value::from(BigInt::from_limbs(negative != 0, limbs))
+                }
+                _ => return Err(DeserializeError::InvalidConstantTag(tag)),
+            };
+            if program.add_constant(constant) != index {
+                return Err(DeserializeError::DuplicateConstant(index));
+            }
+        }
+
+        for index in 0..reader.read_u32()? as usize {
+            if program.add_ident(reader.read_str()?) != index {
+                return Err(DeserializeError::DuplicateIdent(index));
+            }
+        }
+
+        let len = reader.read_u32()? as usize;
+        program.bytes = reader.take(len)?.to_vec();
+
+        while reader.head < bytes.len() {
+            let [tag] = reader.read_arr()?;
+            match tag {
+                SECTION_LINES => {
+                    let mut lines = LineTable::new();
+                    for _ in 0..reader.read_u32()? {
+                        let offset = reader.read_u32()? as usize;
+                        let line = reader.read_u32()?;
+                        let column = reader.read_u32()?;
+                        lines.push(offset, Location { line, column });
+                    }
+                    program.lines = Some(lines);
+                }
+                SECTION_FUNC_NAMES => {
+                    for _ in 0..reader.read_u32()? {
+                        let entry = reader.read_u32()? as usize;
+                        let name = reader.read_str()?.to_owned();
+                        program.func_names.insert(entry, name);
+                    }
+                }
+                _ => return Err(DeserializeError::InvalidSectionTag(tag)),
+            }
+        }
+        Ok(program)
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(&u32::try_from(len).unwrap().to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, str: &str) {
+    write_len(out, str.len());
+    out.extend_from_slice(str.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    head: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DeserializeError> {
+        let slice = self
+            .bytes
+            .get(self.head..self.head + len)
+            .ok_or(DeserializeError::UnexpectedEof)?;
+        self.head += len;
+        Ok(slice)
+    }
+    fn read_arr<const LEN: usize>(&mut self) -> Result<[u8; LEN], DeserializeError> {
+        Ok(self.take(LEN)?.try_into().unwrap())
+    }
+    fn read_u32(&mut self) -> Result<u32, DeserializeError> {
+        self.read_arr().map(u32::from_le_bytes)
+    }
+    fn read_str(&mut self) -> Result<&'a str, DeserializeError> {
+        let len = self.read_u32()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| DeserializeError::InvalidUtf8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::compile_str, vm};
+
+    #[test]
+    fn test_round_trip() {
+        let mut program = compile_str(
+            "\"x\" 1.5 123456789012345678901234567890 2 ** @end 1 ?end 9223372036854775808",
+        );
+        program.store_name("x");
+        let func = program.push_named_func("f", |p| _ = p.push_literal(1));
+        program.call_func(func);
+        let decoded = Program::deserialize(&program.serialize()).unwrap();
+
+        assert_eq!(decoded, program);
+        assert_eq!(decoded.to_string(), program.to_string());
+        assert_eq!(vm::create_and_run(&decoded), vm::create_and_run(&program));
+    }
+
+    #[test]
+    fn test_line_table() {
+        let program = compile_str("1\n  2 +\n3 *");
+        let bytes = program.serialize();
+        let decoded = Program::deserialize(&bytes).unwrap();
+        assert!(decoded.lines.is_some());
+        assert_eq!(decoded.lines, program.lines);
+
+        let mut program = program;
+        program.lines = None;
+        let stripped = program.serialize();
+        assert!(stripped.len() < bytes.len());
+        assert_eq!(Program::deserialize(&stripped).unwrap().lines, None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            Program::deserialize(b"nope"),
+            Err(DeserializeError::BadMagic)
+        );
+        let mut bytes = Program::new().serialize();
+        assert_eq!(
+            Program::deserialize(&bytes[..bytes.len() - 1]),
+            Err(DeserializeError::UnexpectedEof)
+        );
+        bytes.push(7);
+        assert_eq!(
+            Program::deserialize(&bytes),
+            Err(DeserializeError::InvalidSectionTag(7))
+        );
+
+        let mut program = Program::new();
+        program.constants = vec![Value::Int(1), Value::Int(1)];
+        assert_eq!(
+            Program::deserialize(&program.serialize()),
+            Err(DeserializeError::DuplicateConstant(1))
+        );
+        let mut program = Program::new();
+        program.idents = vec!["x".to_owned(), "x".to_owned()];
+        assert_eq!(
+            Program::deserialize(&program.serialize()),
+            Err(DeserializeError::DuplicateIdent(1))
+        );
+    }
+
+    #[test]
+    fn test_deserialized_pools_are_interned() {
+        let mut program = compile_str("\"x\" 1.5 123456789012345678901234567890");
+        program.store_name("x");
+        let mut decoded = Program::deserialize(&program.serialize()).unwrap();
+        assert_eq!(decoded.add_constant(Value::Float(1.5)), 1);
+        assert_eq!(decoded.add_constant(Value::from("x")), 0);
+        assert_eq!(decoded.add_ident("x"), 0);
+        assert_eq!(decoded.constants.len(), program.constants.len());
+        assert_eq!(decoded.idents.len(), 1);
+    }
+}
@@ -1,4 +1,23 @@
-use crate::{op_codes::OpCode, program::Program, value::Value, vm};
+use crate::{
+    assembler::compile_str,
+    binops::BinOpError,
+    lines::Location,
+    op_codes::OpCode,
+    optimize,
+    program::Program,
+    register,
+    traceback::Frame,
+    value::Value,
+    vm::{self, Vm, VmError},
+};
+
+/// Runs `program` with both the stack and the register engine, which must
+/// agree.
+fn run(program: &Program) -> Result<Vec<Value>, VmError> {
+    let stack = vm::create_and_run(program);
+    assert_eq!(register::create_and_run(program), stack);
+    stack
+}
 
 #[test]
 fn test_binary_expressions() {
@@ -14,7 +33,7 @@ fn test_binary_expressions() {
     program.push_opcode(OpCode::Gt);
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run(&program).unwrap();
     assert_eq!(stack, vec![Value::Int(1)]);
 }
 
@@ -31,10 +50,18 @@ fn test_jump() {
     program.push_literal(3);
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run(&program).unwrap();
     assert_eq!(stack, vec![Value::Int(3)]);
 }
 
+/// Runs a program whose branches leave the stack at different depths, which
+/// `vm::create_and_run` rejects.
+fn run_unverified(program: &Program) -> Vec<Value> {
+    let mut vm = vm::Vm::from(program);
+    vm.run().unwrap();
+    vm.stack().to_vec()
+}
+
 #[test]
 fn test_pop_jump_if_false() {
     let mut program = Program::new();
@@ -49,7 +76,7 @@ fn test_pop_jump_if_false() {
     program.push_literal(3);
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run_unverified(&program);
     assert_eq!(stack, vec![Value::Int(3)]);
 }
 
@@ -64,7 +91,7 @@ fn test_if_true() {
     program.push_literal("World!");
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run_unverified(&program);
     assert_eq!(stack, vec![Value::from("Hello, "), Value::from("World!")]);
 }
 
@@ -79,7 +106,7 @@ fn test_if_false() {
     program.push_literal("World!");
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run_unverified(&program);
     assert_eq!(stack, vec![Value::from("World!")]);
 }
 
@@ -99,7 +126,7 @@ fn test_if_else_true() {
     program.push_literal("World!");
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run(&program).unwrap();
     assert_eq!(stack, vec![Value::from("Hello, "), Value::from("World!")]);
 }
 
@@ -119,7 +146,7 @@ fn test_if_else_false() {
     program.push_literal("World!");
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run(&program).unwrap();
     assert_eq!(stack, vec![Value::from("Goodbye, "), Value::from("World!")]);
 }
 
@@ -142,7 +169,7 @@ fn test_while_loop() {
     );
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run(&program).unwrap();
     assert_eq!(stack, vec![Value::Int(10)]);
 }
 
@@ -175,10 +202,132 @@ fn while_range_product() {
     );
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run(&program).unwrap();
     assert_eq!(stack, vec![Value::Int(5 * 4 * 3 * 2), Value::Int(5)]);
 }
 
+/// Pushes `name += value`.
+fn add_to(program: &mut Program, name: &str, value: i64) {
+    program.load_name(name);
+    program.push_literal(value);
+    program.push_opcode(OpCode::Add);
+    program.store_name(name);
+}
+
+#[test]
+fn test_break_continue() {
+    let mut program = Program::new();
+    program.push_literal(0);
+    program.store_name("i");
+    program.push_literal(0);
+    program.store_name("sum");
+    program.push_while_loop(
+        |condition| {
+            condition.load_name("i");
+            condition.push_literal(10);
+            condition.push_opcode(OpCode::Lt);
+        },
+        |body| {
+            add_to(body, "i", 1);
+            // Skip odd numbers.
+            body.load_name("i");
+            body.push_literal(2);
+            body.push_opcode(OpCode::Mod);
+            let next = body.continue_label();
+            body.pop_jump_if_true_to(next);
+            // Stop after 7.
+            body.load_name("i");
+            body.push_literal(7);
+            body.push_opcode(OpCode::Gt);
+            let end = body.break_label();
+            body.push_if(|body| body.jump_to(end));
+
+            body.load_name("i");
+            body.load_name("sum");
+            body.push_opcode(OpCode::Add);
+            body.store_name("sum");
+        },
+    );
+    program.load_name("i");
+    program.load_name("sum");
+
+    let program = program.finish().unwrap();
+    eprintln!("{program}");
+    assert_eq!(
+        run(&program).unwrap(),
+        [Value::Int(8), Value::Int(2 + 4 + 6)]
+    );
+}
+
+#[test]
+fn test_nested_loops() {
+    // The inner `break_` only leaves the inner loop.
+    let mut program = Program::new();
+    program.push_literal(0);
+    program.store_name("count");
+    program.push_for_range("i", 0..3, |outer| {
+        outer.push_for_range("j", 0..3, |inner| {
+            inner.load_name("j");
+            inner.load_name("i");
+            inner.push_opcode(OpCode::Eq);
+            let end = inner.new_label();
+            inner.pop_jump_if_false_to(end);
+            inner.break_();
+            inner.bind(end);
+            add_to(inner, "count", 1);
+        });
+    });
+    program.load_name("count");
+    assert_eq!(run(&program.finish().unwrap()).unwrap(), [Value::Int(3)]);
+
+    // Jumping to the outer loop's break label leaves both.
+    let mut program = Program::new();
+    program.push_for_range("i", 0..10, |outer| {
+        let end = outer.break_label();
+        outer.push_for_range("j", 0..10, |inner| {
+            inner.load_name("i");
+            inner.load_name("j");
+            inner.push_opcode(OpCode::Mul);
+            inner.push_literal(6);
+            inner.push_opcode(OpCode::Eq);
+            inner.push_if(|body| body.jump_to(end));
+        });
+    });
+    program.load_name("i");
+    program.load_name("j");
+    assert_eq!(
+        run(&program.finish().unwrap()).unwrap(),
+        [Value::Int(1), Value::Int(6)]
+    );
+}
+
+#[test]
+fn test_for_range() {
+    let mut program = Program::new();
+    program.push_literal(0);
+    program.store_name("sum");
+    program.push_for_range("i", 0..5, |body| {
+        body.load_name("i");
+        body.push_literal(2);
+        body.push_opcode(OpCode::Eq);
+        let end = body.new_label();
+        body.pop_jump_if_false_to(end);
+        body.continue_();
+        body.bind(end);
+
+        body.load_name("sum");
+        body.load_name("i");
+        body.push_opcode(OpCode::Add);
+        body.store_name("sum");
+    });
+    program.load_name("i");
+    program.load_name("sum");
+
+    let program = program.finish().unwrap();
+    eprintln!("{program}");
+    assert_eq!(run(&program).unwrap(), [Value::Int(5), Value::Int(8)]);
+}
+
 #[test]
 fn test_load_store_name() {
     let mut program = Program::new();
@@ -189,10 +338,23 @@ fn test_load_store_name() {
     program.load_name("x");
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run(&program).unwrap();
     assert_eq!(stack, vec![Value::Int(1), Value::Int(1)]);
 }
 
+#[test]
+fn test_undefined_variable() {
+    let mut program = Program::new();
+
+    program.load_name("x");
+    program.push_literal(1);
+    program.store_name("x");
+
+    let err = run(&program).unwrap_err();
+    assert_eq!(err.kind(), &vm::VmError::UndefinedVariable("x".to_owned()));
+    assert_eq!(err.to_string(), "undefined variable 'x' at offset 0");
+}
+
 #[test]
 fn test_func_call() {
     let mut program = Program::new();
@@ -207,6 +369,370 @@ fn test_func_call() {
     program.push_literal(2);
 
     eprintln!("{program}");
-    let stack = vm::create_and_run(&program);
+    let stack = run(&program).unwrap();
     assert_eq!(stack, vec![Value::Int(4), Value::Int(2)]);
 }
+
+/// A function that counts its argument down to zero by calling itself, with
+/// either `call_func; Ret` or `tail_call_func`.
+fn countdown(n: i64, tail: bool) -> Program {
+    let mut program = Program::new();
+    let func = program.push_func(|func| {
+        let entry = func.len();
+        func.push_opcode(OpCode::Dup);
+        func.push_if(|body| {
+            body.push_literal(1);
+            body.push_opcode(OpCode::Sub);
+            if tail {
+                body.tail_call_func(entry);
+            } else {
+                body.call_func(entry);
+                body.push_opcode(OpCode::Ret);
+            }
+        });
+    });
+    program.push_literal(n);
+    program.call_func(func);
+    program
+}
+
+#[test]
+fn test_tail_call() {
+    let program = countdown(1_000_000, true);
+    eprintln!("{program}");
+
+    let mut vm = Vm::from(&program);
+    let mut max_depth = 0;
+    while vm.head() < program.len() {
+        vm.run_next().unwrap();
+        max_depth = max_depth.max(vm.call_depth());
+    }
+    assert_eq!(max_depth, 1);
+    assert_eq!(vm.stack(), [Value::Int(0)]);
+
+    assert_eq!(run(&program).unwrap(), vec![Value::Int(0)]);
+}
+
+#[test]
+fn test_eliminate_tail_calls() {
+    let program = optimize::eliminate_tail_calls(&countdown(1_000_000, false));
+    eprintln!("{program}");
+    assert_eq!(program, countdown(1_000_000, true));
+    assert_eq!(run(&program).unwrap(), vec![Value::Int(0)]);
+}
+
+#[test]
+fn test_assemble_arithmetic() {
+    let program = compile_str("7 2 % 2 10 ** 0 7 - 2 ~/ 1 2 != 5 2 /");
+    eprintln!("{program}");
+    let stack = run(&program).unwrap();
+    assert_eq!(
+        stack,
+        vec![
+            Value::Int(1),
+            Value::Int(1024),
+            Value::Int(-4),
+            Value::Int(1),
+            Value::Float(2.5)
+        ]
+    );
+}
+
+#[test]
+fn test_assemble_stack_ops() {
+    let cases = [
+        ("1 2 3 rot", vec![2, 3, 1]),
+        ("1 2 over", vec![1, 2, 1]),
+        ("1 2 3 pick 2", vec![1, 2, 3, 1]),
+        ("1 2 3 pick 0", vec![1, 2, 3, 3]),
+        ("1 2 3 4 roll 3", vec![2, 3, 4, 1]),
+        ("1 2 3 roll 1", vec![1, 3, 2]),
+        ("1 2 3 pop_n 2", vec![1]),
+        ("1 2 3 pop_n 0", vec![1, 2, 3]),
+    ];
+    for (source, expected) in cases {
+        let stack = run(&compile_str(source)).unwrap();
+        assert_eq!(
+            stack,
+            expected.into_iter().map(Value::Int).collect::<Vec<_>>()
+        );
+    }
+
+    let program = compile_str(include_str!("../examples/factorial.pty"));
+    eprintln!("{program}");
+    assert_eq!(run(&program).unwrap(), vec![Value::Int(120)]);
+}
+
+#[test]
+fn test_assemble_conditional_jumps() {
+    let cases = [
+        ("1 2 &&end 3 @end", vec![1, 3]),
+        ("1 0 &&end 3 @end", vec![1, 0]),
+        ("1 0 ||end 3 @end", vec![1, 3]),
+        ("1 2 ||end 3 @end", vec![1, 2]),
+        ("1 !?end 2 pop @end 3", vec![3]),
+        ("0 !?end 2 pop @end 3", vec![3]),
+        // while !(n == 0) { n -= 1 }
+        ("5 @start dup 0 = !?end 1 - $start @end", vec![0]),
+    ];
+    for (source, expected) in cases {
+        let stack = run(&compile_str(source)).unwrap();
+        assert_eq!(
+            stack,
+            expected.into_iter().map(Value::Int).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn test_short_circuit() {
+    // `1 0 %` raises a division by zero error, so it must only run when needed.
+    let fails = |p: &mut Program| {
+        p.push_literal(1);
+        p.push_literal(0);
+        p.push_opcode(OpCode::Mod);
+    };
+    for (lhs, rhs, and, or) in [(0, 5, 0, 5), (3, 0, 0, 3), (3, 5, 5, 3), (0, 0, 0, 0)] {
+        let mut program = Program::new();
+        program.push_and(|p| _ = p.push_literal(lhs), |p| _ = p.push_literal(rhs));
+        program.push_or(|p| _ = p.push_literal(lhs), |p| _ = p.push_literal(rhs));
+        assert_eq!(run(&program).unwrap(), [Value::Int(and), Value::Int(or)]);
+    }
+
+    let mut program = Program::new();
+    program.push_and(|p| _ = p.push_literal(0), fails);
+    program.push_or(|p| _ = p.push_literal(7), fails);
+    eprintln!("{program}");
+    assert_eq!(run(&program).unwrap(), [Value::Int(0), Value::Int(7)]);
+
+    let mut program = Program::new();
+    program.push_or(|p| _ = p.push_literal(0), fails);
+    assert_eq!(
+        run(&program).unwrap_err().kind(),
+        &VmError::BinOp(BinOpError::ZeroDivision)
+    );
+}
+
+#[test]
+fn test_int_div_is_not_a_comment() {
+    let program = compile_str("9 2 ~/ // 9 2 ~/\n");
+    let stack = run(&program).unwrap();
+    assert_eq!(stack, vec![Value::Int(4)]);
+}
+
+#[test]
+fn test_zero_division_error() {
+    let program = compile_str("1 0 %");
+    let err = run(&program).unwrap_err();
+    assert_eq!(err.kind(), &vm::VmError::BinOp(BinOpError::ZeroDivision));
+}
+
+#[test]
+fn test_error_location() {
+    let program = compile_str("1 2 +\n  3 0\n  %");
+    let err = run(&program).unwrap_err();
+    assert_eq!(
+        err,
+        VmError::At {
+            error: Box::new(VmError::BinOp(BinOpError::ZeroDivision)),
+            traceback: vec![Frame {
+                entry: 0,
+                name: None,
+                return_address: None,
+                offset: 21,
+                location: Some(Location { line: 3, column: 3 }),
+            }],
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "integer division by zero at line 3, column 3"
+    );
+
+    let mut vm = Vm::from(&program);
+    assert_eq!(vm.run(), Err(err));
+
+    // Folding `1 2 +` moves the `%`, but not its line.
+    let folded = optimize::fold_constants(&program);
+    assert!(folded.len() < program.len());
+    let err = run(&folded).unwrap_err();
+    let [frame] = err.traceback() else {
+        panic!("{err:?}")
+    };
+    assert_eq!(frame.offset, 15);
+    assert_eq!(frame.location, Some(Location { line: 3, column: 3 }));
+}
+
+#[test]
+fn test_traceback() {
+    let mut program = Program::new();
+    let inner = program.push_named_func("inner", |func| {
+        func.push_literal(0);
+        func.push_opcode(OpCode::Mod);
+    });
+    let outer = program.push_func(|func| {
+        func.push_literal(1);
+        func.call_func(inner);
+    });
+    program.push_literal(7);
+    program.call_func(outer);
+
+    let err = run(&program).unwrap_err();
+    assert_eq!(Vm::from(&program).run(), Err(err.clone()));
+    assert_eq!(err.kind(), &VmError::BinOp(BinOpError::ZeroDivision));
+    let frame = |entry, name: Option<&str>, return_address, offset| Frame {
+        entry,
+        name: name.map(str::to_owned),
+        return_address,
+        offset,
+        location: None,
+    };
+    assert_eq!(
+        err.traceback(),
+        [
+            frame(0, None, None, 35),
+            frame(17, None, Some(40), 23),
+            frame(5, Some("inner"), Some(28), 10),
+        ]
+    );
+    let lines: Vec<String> = err.traceback().iter().map(ToString::to_string).collect();
+    assert_eq!(
+        lines,
+        [
+            "<top level>, offset 35",
+            "function at 17, offset 23",
+            "inner, offset 10"
+        ]
+    );
+    assert_eq!(err.to_string(), "integer division by zero at offset 10");
+
+    // `outer` returns straight after calling `inner`, so a tail call replaces
+    // its frame.
+    let program = optimize::eliminate_tail_calls(&program);
+    let err = run(&program).unwrap_err();
+    assert_eq!(Vm::from(&program).run(), Err(err.clone()));
+    let [top, inner] = err.traceback() else {
+        panic!("{err:?}")
+    };
+    assert_eq!(top.return_address, None);
+    assert_eq!(inner.name.as_deref(), Some("inner"));
+    assert_eq!(inner.return_address, Some(program.len()));
+}
+
+#[test]
+fn test_assemble_bitwise() {
+    let program = compile_str("12 10 & 12 10 | 12 10 ^ 0 ~ 1 4 << 256 4 >>");
+    eprintln!("{program}");
+    let stack = run(&program).unwrap();
+    assert_eq!(
+        stack,
+        vec![
+            Value::Int(8),
+            Value::Int(14),
+            Value::Int(6),
+            Value::Int(-1),
+            Value::Int(16),
+            Value::Int(16),
+        ]
+    );
+}
+
+#[test]
+fn test_bitwise_type_error() {
+    let program = compile_str("1.5 1 &");
+    let err = run(&program).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        vm::VmError::BinOp(BinOpError::Unsupported { .. })
+    ));
+}
+
+#[test]
+fn test_overflow_promotes_to_bigint() {
+    let mut program = Program::new();
+    program.push_literal(i64::MAX);
+    program.push_literal(1);
+    program.push_opcode(OpCode::Add);
+    program.push_opcode(OpCode::Dup);
+    program.push_literal(1);
+    program.push_opcode(OpCode::Sub);
+
+    let stack = run(&program).unwrap();
+    assert_eq!(
+        stack,
+        vec![
+            Value::BigInt("9223372036854775808".parse().unwrap()),
+            Value::Int(i64::MAX)
+        ]
+    );
+}
+
+#[test]
+fn test_assemble_bigint_literal() {
+    let program = compile_str("123456789012345678901234567890 123456789012345678901234567889 -");
+    eprintln!("{program}");
+    assert!(program
+        .to_string()
+        .contains("LoadConst 0 123456789012345678901234567890"));
+    let stack = run(&program).unwrap();
+    assert_eq!(stack, vec![Value::Int(1)]);
+}
+
+#[test]
+fn test_assemble_comparisons() {
+    let program = compile_str("1 2.0 < 2 2.0 = \"a\" \"b\" < \"a\" 1 = 1 \"a\" !=");
+    let stack = run(&program).unwrap();
+    assert_eq!(
+        stack,
+        vec![
+            Value::Int(1),
+            Value::Int(1),
+            Value::Int(1),
+            Value::Int(0),
+            Value::Int(1)
+        ]
+    );
+
+    let program = compile_str("\"a\" 1 <");
+    let err = run(&program).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        vm::VmError::BinOp(BinOpError::Incomparable { .. })
+    ));
+}
+
+#[test]
+fn test_interning() {
+    let mut program = Program::new();
+    for i in 0..1000 {
+        program.push_literal(format!("s{}", i % 100));
+        program.push_opcode(OpCode::Pop);
+    }
+    assert_eq!(program.constants.len(), 100);
+    assert_eq!(program.constants[42], Value::from("s42"));
+
+    assert_eq!(program.push_literal(0.0), 100);
+    assert_eq!(program.push_literal(-0.0), 101);
+    assert_eq!(program.push_literal(f64::NAN), 102);
+    assert_eq!(program.push_literal(f64::NAN), 102);
+    assert_eq!(program.push_literal(1), 103);
+    assert_eq!(program.push_literal(1.0), 104);
+    assert_eq!(program.push_literal(-0.0), 101);
+
+    assert_eq!(program.store_name("x"), 0);
+    assert_eq!(program.load_name("y"), 1);
+    assert_eq!(program.load_name("x"), 0);
+}
+
+#[test]
+fn test_interning_after_direct_edits() {
+    let mut program = Program::new();
+    program.push_literal(1);
+    program.constants.push(Value::from("pushed"));
+    assert_eq!(program.push_literal("pushed"), 1);
+
+    program.constants = vec![Value::from("replaced"), Value::Int(1)];
+    assert_eq!(program.push_literal(1), 1);
+    assert_eq!(program.push_literal("replaced"), 0);
+    assert_eq!(program.constants.len(), 2);
+}
@@ -0,0 +1,71 @@
+//! The calls that were active when a program failed.
+
+use std::fmt;
+
+use crate::{lines::Location, program::Program};
+
+/// A function that was running when an error was raised.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Offset of the function's first instruction, or 0 for the top level.
+    pub entry: usize,
+    /// The function's name, if the program records one.
+    pub name: Option<String>,
+    /// Where the function returns to, or `None` for the top level.
+    pub return_address: Option<usize>,
+    /// The instruction the frame was at: the one that failed in the
+    /// innermost frame and the call in the others.
+    pub offset: usize,
+    pub location: Option<Location>,
+}
+
+impl Frame {
+    /// Writes where in the program the frame was.
+    pub(crate) fn write_position(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location {
+            Some(location) => write!(f, "line {}, column {}", location.line, location.column),
+            None => write!(f, "offset {}", self.offset),
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.name, self.return_address) {
+            (Some(name), _) => write!(f, "{name}")?,
+            (None, Some(_)) => write!(f, "function at {}", self.entry)?,
+            (None, None) => write!(f, "<top level>")?,
+        }
+        write!(f, ", ")?;
+        self.write_position(f)
+    }
+}
+
+/// Builds the frames of a traceback, outermost first. `calls` holds the
+/// entry and return address of each call that has not returned, and
+/// `offset` is the instruction that failed.
+pub(crate) fn capture(
+    program: &Program,
+    calls: impl IntoIterator<Item = (usize, usize)>,
+    offset: usize,
+) -> Vec<Frame> {
+    let mut frames: Vec<Frame> = [(0, None)]
+        .into_iter()
+        .chain(calls.into_iter().map(|(entry, ret)| (entry, Some(ret))))
+        .map(|(entry, return_address)| Frame {
+            entry,
+            name: return_address.and_then(|_| program.func_names.get(&entry).cloned()),
+            return_address,
+            offset,
+            location: None,
+        })
+        .collect();
+    // A caller is at the `Jump` of its call, right before the return address.
+    for i in 1..frames.len() {
+        frames[i - 1].offset = frames[i].return_address.unwrap() - 5;
+    }
+    for frame in &mut frames {
+        frame.location = program.location(frame.offset);
+    }
+    frames
+}
@@ -1,16 +1,40 @@
-use std::{borrow::Cow, fmt};
+use std::{fmt, rc::Rc};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+use crate::bigint::BigInt;
+
+/// A runtime value.
+///
+/// `==` on `Value` compares structurally and is meant for tests; scripts
+/// compare values with [`Value::equals`] and [`Value::compare`].
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Int(i64),
+    /// An integer outside the range of `i64`. Integers that fit in an `i64`
+    /// are always stored as `Int`.
+    BigInt(BigInt),
     Float(f64),
-    Str(Cow<'static, str>),
+    /// Shared so that cloning a string (`LoadConst`, `Dup`, `LoadName`) only
+    /// bumps a reference count. It is an `Rc<String>` rather than an
+    /// `Rc<str>` so `+` can append in place when the string is not shared.
+    Str(Rc<String>),
+}
+
+impl Value {
+    #[must_use]
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Int(_) | Self::BigInt(_) => "int",
+            Self::Float(_) => "float",
+            Self::Str(_) => "str",
+        }
+    }
 }
 
 impl From<&Value> for bool {
     fn from(value: &Value) -> Self {
         match value {
             Value::Int(int) => *int != 0,
+            Value::BigInt(_) => true,
             Value::Str(str) => str.is_empty(),
             Value::Float(float) => *float != 0.0,
         }
@@ -23,6 +47,15 @@ impl From<i64> for Value {
     }
 }
 
+impl From<BigInt> for Value {
+    fn from(value: BigInt) -> Self {
+        match value.to_i64() {
+            Some(int) => Self::Int(int),
+            None => Self::BigInt(value),
+        }
+    }
+}
+
 impl From<f64> for Value {
     fn from(value: f64) -> Self {
         Self::Float(value)
@@ -31,13 +64,13 @@ impl From<f64> for Value {
 
 impl From<&'static str> for Value {
     fn from(value: &'static str) -> Self {
-        Self::Str(Cow::Borrowed(value))
+        Self::Str(Rc::new(value.to_owned()))
     }
 }
 
 impl From<String> for Value {
     fn from(value: String) -> Self {
-        Self::Str(Cow::Owned(value))
+        Self::Str(Rc::new(value))
     }
 }
 
@@ -51,6 +84,7 @@ impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Int(int) => write!(f, "{int}"),
+            Self::BigInt(int) => write!(f, "{int}"),
             Self::Float(float) => write!(f, "{float}"),
             Self::Str(str) => write!(f, "'{str}'"),
         }
@@ -0,0 +1,706 @@
+use std::fmt;
+
+use crate::{
+    cfg::Cfg,
+    instruction::{DecodeError, Instruction},
+    op_codes::StackEffect,
+    program::Program,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    Decode(DecodeError),
+    InvalidConstant {
+        offset: usize,
+        index: usize,
+    },
+    InvalidIdent {
+        offset: usize,
+        index: usize,
+    },
+    /// A `PrepareFuncCall` that is not followed by a `Jump`.
+    InvalidCall {
+        offset: usize,
+    },
+    StackUnderflow {
+        offset: usize,
+    },
+    /// Two paths reach the instruction at `offset` with different depths.
+    MismatchedDepth {
+        offset: usize,
+        expected: isize,
+        found: isize,
+    },
+    /// Two `Ret`s of the same function leave the stack at different depths.
+    MismatchedReturn {
+        offset: usize,
+        expected: isize,
+        found: isize,
+    },
+    /// A `Ret` or `TailCall` reached from the top level, where there is no
+    /// caller to return to.
+    ReturnFromTopLevel {
+        offset: usize,
+    },
+    /// A recursive function pops more values the deeper it recurses.
+    UnboundedArgs {
+        entry: usize,
+    },
+}
+
+impl From<DecodeError> for VerifyError {
+    fn from(value: DecodeError) -> Self {
+        Self::Decode(value)
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Decode(err) => write!(f, "{err}"),
+            Self::InvalidConstant { offset, index } => {
+                write!(f, "invalid constant {index} at offset {offset}")
+            }
+            Self::InvalidIdent { offset, index } => {
+                write!(f, "invalid identifier {index} at offset {offset}")
+            }
+            Self::InvalidCall { offset } => {
+                write!(f, "PrepareFuncCall at offset {offset} is not followed by a Jump")
+            }
+            Self::StackUnderflow { offset } => write!(f, "stack underflow at offset {offset}"),
+            Self::MismatchedDepth {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "stack depth {found} at offset {offset} does not match depth {expected} from another path"
+            ),
+            Self::MismatchedReturn {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "return at offset {offset} leaves {found} values but another return leaves {expected}"
+            ),
+            Self::ReturnFromTopLevel { offset } => {
+                write!(f, "return at offset {offset} is outside any function")
+            }
+            Self::UnboundedArgs { entry } => {
+                write!(f, "function at offset {entry} pops an unbounded number of values")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Stack usage of a function, relative to the depth it is entered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionInfo {
+    /// Offset of the first instruction.
+    pub entry: usize,
+    /// How many values the function pops from below its entry depth.
+    pub args: usize,
+    /// Change in depth from entry to `Ret`, or `None` if it never returns.
+    pub net: Option<isize>,
+    /// The most values the function and its callees push above the entry
+    /// depth, or `None` if it recurses.
+    pub max_depth: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackInfo {
+    /// The top level of the program, followed by every called function in
+    /// order of entry.
+    pub functions: Vec<FunctionInfo>,
+    /// For each function, the depth before every instruction it reaches,
+    /// relative to its entry depth. Instructions are numbered in the order
+    /// [`Program::instructions`] yields them.
+    pub depths: Vec<Vec<Option<isize>>>,
+    /// The control-flow graph the program was checked with, so engines that
+    /// translate the program need not build it again.
+    pub cfg: Cfg,
+}
+
+impl StackInfo {
+    /// The deepest the stack gets while running the program.
+    #[must_use]
+    pub fn max_depth(&self) -> Option<usize> {
+        self.functions[0].max_depth
+    }
+}
+
+/// Checks that every operand is valid and that the stack depth at each
+/// instruction is the same along every path to it, and computes how deep
+/// the stack gets.
+///
+/// Functions are summarised by how many values they pop and their net
+/// effect, and calls are checked against those summaries. The top level may
+/// not pop values it did not push, and may not return.
+///
+/// # Errors
+/// Fails on the first problem found.
+pub fn verify(program: &Program) -> Result<StackInfo, VerifyError> {
+    let cfg = Cfg::new(program)?;
+    if cfg.blocks.is_empty() {
+        let main = FunctionInfo {
+            entry: 0,
+            args: 0,
+            net: None,
+            max_depth: Some(0),
+        };
+        return Ok(StackInfo {
+            functions: vec![main],
+            depths: vec![vec![]],
+            cfg,
+        });
+    }
+
+    let mut entries: Vec<usize> = (0..cfg.instructions.len())
+        .filter_map(|i| match cfg.instructions[i].1 {
+            Instruction::Jump(target) if is_call(&cfg, i) => Some(target),
+            Instruction::TailCall(target) => Some(target),
+            _ => None,
+        })
+        .filter_map(|target| cfg.block_at(target as usize))
+        .chain([0])
+        .collect();
+    entries.sort_unstable();
+    entries.dedup();
+
+    let mut verifier = Verifier {
+        program,
+        summaries: vec![Summary::default(); entries.len()],
+        entries,
+        cfg,
+    };
+    let functions = verifier.entries.len();
+    // Summaries only grow, and stop growing unless a recursive function pops
+    // more values each time it recurses.
+    let mut changed = 0;
+    for _ in 0..(functions + 1) * (functions + 1) {
+        let mut walks = Vec::with_capacity(functions);
+        let mut converged = true;
+        for func in 0..functions {
+            let walk = verifier.walk(func)?;
+            let summary = Summary {
+                args: usize::try_from(-walk.min).unwrap(),
+                net: walk.net,
+            };
+            if verifier.summaries[func] != summary {
+                verifier.summaries[func] = summary;
+                changed = func;
+                converged = false;
+            }
+            walks.push(walk);
+        }
+        if converged {
+            return Ok(verifier.finish(&walks));
+        }
+    }
+    Err(VerifyError::UnboundedArgs {
+        entry: verifier.offset_of(verifier.entries[changed]),
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Summary {
+    args: usize,
+    net: Option<isize>,
+}
+
+/// The result of following every path through one function.
+struct Walk {
+    min: isize,
+    max: isize,
+    net: Option<isize>,
+    exit: Option<isize>,
+    /// The depth at each call and the called function.
+    calls: Vec<(isize, usize)>,
+    /// The depth before each instruction.
+    depths: Vec<Option<isize>>,
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    cfg: Cfg,
+    /// The entry block of each function, starting with the top level.
+    entries: Vec<usize>,
+    summaries: Vec<Summary>,
+}
+
+impl Verifier<'_> {
+    #[allow(clippy::cast_possible_wrap)]
+    fn walk(&self, func: usize) -> Result<Walk, VerifyError> {
+        let is_main = func == 0;
+        let mut walk = Walk {
+            min: 0,
+            max: 0,
+            net: None,
+            exit: None,
+            calls: vec![],
+            depths: vec![None; self.cfg.instructions.len()],
+        };
+        let mut depths = vec![None; self.cfg.blocks.len()];
+        let mut stack = vec![(self.entries[func], 0)];
+        while let Some((b, mut depth)) = stack.pop() {
+            let offset = self.offset_of(b);
+            match depths[b] {
+                Some(expected) if expected != depth => {
+                    return Err(VerifyError::MismatchedDepth {
+                        offset,
+                        expected,
+                        found: depth,
+                    })
+                }
+                Some(_) => continue,
+                None => depths[b] = Some(depth),
+            }
+
+            let block = &self.cfg.blocks[b];
+            for i in block.start..block.end {
+                walk.depths[i] = Some(depth);
+                let effect = self.check(i)?;
+                depth -= effect.pops as isize;
+                if depth < walk.min {
+                    if is_main {
+                        let offset = self.cfg.instructions[i].0;
+                        return Err(VerifyError::StackUnderflow { offset });
+                    }
+                    walk.min = depth;
+                }
+                depth += effect.pushes as isize;
+                walk.max = walk.max.max(depth);
+            }
+
+            let last = block.end - 1;
+            let (offset, inst) = self.cfg.instructions[last];
+            let next = offset + inst.size();
+            let mut targets = vec![];
+            if is_main && matches!(inst, Instruction::Ret | Instruction::TailCall(_)) {
+                return Err(VerifyError::ReturnFromTopLevel { offset });
+            }
+            match inst {
+                Instruction::Ret => merge(&mut walk.net, depth, |expected| {
+                    VerifyError::MismatchedReturn {
+                        offset,
+                        expected,
+                        found: depth,
+                    }
+                })?,
+                Instruction::Jump(target) if is_call(&self.cfg, last) => {
+                    // Return sites are only followed once the callee is known
+                    // to return.
+                    if let Some(net) = self.call(&mut walk, is_main, offset, target, depth)? {
+                        targets.push((next, depth + net));
+                    }
+                }
+                Instruction::TailCall(target) => {
+                    // The callee returns to our caller.
+                    if let Some(net) = self.call(&mut walk, is_main, offset, target, depth)? {
+                        let found = depth + net;
+                        merge(&mut walk.net, found, |expected| {
+                            VerifyError::MismatchedReturn {
+                                offset,
+                                expected,
+                                found,
+                            }
+                        })?;
+                    }
+                }
+                Instruction::Jump(target) => targets.push((target as usize, depth)),
+                Instruction::PopJumpIfFalse(target)
+                | Instruction::PopJumpIfTrue(target)
+                | Instruction::JumpIfNotLtConst(_, target) => {
+                    targets.push((next, depth));
+                    targets.push((target as usize, depth));
+                }
+                // The value is left on the stack only if the jump is taken.
+                Instruction::JumpIfFalseOrPop(target) | Instruction::JumpIfTrueOrPop(target) => {
+                    targets.push((next, depth - 1));
+                    targets.push((target as usize, depth));
+                }
+                _ => targets.push((next, depth)),
+            }
+            for (target, depth) in targets {
+                match self.cfg.block_at(target) {
+                    Some(b) => stack.push((b, depth)),
+                    None => merge(&mut walk.exit, depth, |expected| {
+                        VerifyError::MismatchedDepth {
+                            offset: target,
+                            expected,
+                            found: depth,
+                        }
+                    })?,
+                }
+            }
+        }
+        Ok(walk)
+    }
+
+    /// Records a call to `target` at `depth` and returns the callee's net
+    /// effect, if it is known to return.
+    #[allow(clippy::cast_possible_wrap)]
+    fn call(
+        &self,
+        walk: &mut Walk,
+        is_main: bool,
+        offset: usize,
+        target: u32,
+        depth: isize,
+    ) -> Result<Option<isize>, VerifyError> {
+        let Some(callee) = self.cfg.block_at(target as usize) else {
+            // Calling the end of the program ends it.
+            return Ok(None);
+        };
+        let callee = self.entries.binary_search(&callee).unwrap();
+        let summary = self.summaries[callee];
+        let lowest = depth - summary.args as isize;
+        if lowest < walk.min {
+            if is_main {
+                return Err(VerifyError::StackUnderflow { offset });
+            }
+            walk.min = lowest;
+        }
+        walk.calls.push((depth, callee));
+        Ok(summary.net)
+    }
+
+    /// Checks the operand of the `i`th instruction and returns its effect.
+    fn check(&self, i: usize) -> Result<StackEffect, VerifyError> {
+        let (offset, inst) = self.cfg.instructions[i];
+        if let Some(index) = inst.constant() {
+            let index = index as usize;
+            if index >= self.program.constants.len() {
+                return Err(VerifyError::InvalidConstant { offset, index });
+            }
+        }
+        match inst {
+            Instruction::StoreName(index)
+            | Instruction::LoadName(index)
+            | Instruction::IncName(index, _)
+                if index as usize >= self.program.idents.len() =>
+            {
+                let index = index as usize;
+                Err(VerifyError::InvalidIdent { offset, index })
+            }
+            Instruction::PrepareFuncCall
+                if !matches!(
+                    self.cfg.instructions.get(i + 1),
+                    Some((_, Instruction::Jump(_)))
+                ) =>
+            {
+                Err(VerifyError::InvalidCall { offset })
+            }
+            _ => Ok(inst.stack_effect()),
+        }
+    }
+
+    fn offset_of(&self, block: usize) -> usize {
+        self.cfg.instructions[self.cfg.blocks[block].start].0
+    }
+
+    fn finish(self, walks: &[Walk]) -> StackInfo {
+        let mut max_depths = vec![MaxDepth::Unvisited; walks.len()];
+        for func in 0..walks.len() {
+            max_depth(walks, &mut max_depths, func);
+        }
+        let functions = walks
+            .iter()
+            .zip(max_depths)
+            .enumerate()
+            .map(|(func, (walk, max_depth))| FunctionInfo {
+                entry: self.offset_of(self.entries[func]),
+                args: self.summaries[func].args,
+                net: walk.net,
+                max_depth: match max_depth {
+                    MaxDepth::Done(max) => max.map(|max| usize::try_from(max).unwrap()),
+                    _ => unreachable!(),
+                },
+            })
+            .collect();
+        let depths = walks.iter().map(|walk| walk.depths.clone()).collect();
+        StackInfo {
+            functions,
+            depths,
+            cfg: self.cfg,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MaxDepth {
+    Unvisited,
+    Visiting,
+    Done(Option<isize>),
+}
+
+/// The highest depth reached by `func` and the functions it calls, or `None`
+/// if it is part of or calls into a cycle of calls.
+fn max_depth(walks: &[Walk], max_depths: &mut [MaxDepth], func: usize) -> Option<isize> {
+    match max_depths[func] {
+        MaxDepth::Visiting => return None,
+        MaxDepth::Done(max) => return max,
+        MaxDepth::Unvisited => max_depths[func] = MaxDepth::Visiting,
+    }
+    let mut max = Some(walks[func].max);
+    for &(depth, callee) in &walks[func].calls {
+        let callee_max = max_depth(walks, max_depths, callee);
+        max = max
+            .zip(callee_max)
+            .map(|(max, callee)| max.max(depth + callee));
+    }
+    max_depths[func] = MaxDepth::Done(max);
+    max
+}
+
+fn merge(
+    slot: &mut Option<isize>,
+    depth: isize,
+    err: impl FnOnce(isize) -> VerifyError,
+) -> Result<(), VerifyError> {
+    match *slot {
+        Some(expected) if expected != depth => Err(err(expected)),
+        _ => {
+            *slot = Some(depth);
+            Ok(())
+        }
+    }
+}
+
+/// Whether the `i`th instruction is the `Jump` of a function call.
+fn is_call(cfg: &Cfg, i: usize) -> bool {
+    i > 0
+        && matches!(cfg.instructions[i].1, Instruction::Jump(_))
+        && cfg.instructions[i - 1].1 == Instruction::PrepareFuncCall
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembler::compile_str, op_codes::OpCode, value::Value, vm};
+
+    fn max_depth(source: &str) -> Option<usize> {
+        verify(&compile_str(source)).unwrap().max_depth()
+    }
+
+    #[test]
+    fn test_max_depth() {
+        assert_eq!(max_depth(""), Some(0));
+        assert_eq!(max_depth("1 2 3 + +"), Some(3));
+        assert_eq!(max_depth("1 2 + 3 +"), Some(2));
+        assert_eq!(
+            max_depth(include_str!("../examples/while_loop.pty")),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn test_mismatched_depth() {
+        let program = compile_str("1 ?a 5 @a");
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError::MismatchedDepth {
+                offset: 15,
+                expected: 0,
+                found: 1
+            })
+        );
+        assert_eq!(
+            vm::create_and_run(&program),
+            Err(vm::VmError::Verify(VerifyError::MismatchedDepth {
+                offset: 15,
+                expected: 0,
+                found: 1
+            }))
+        );
+
+        let program = compile_str("0 @start dup 3 < ?end 1 + 0 $start @end");
+        assert!(matches!(
+            verify(&program),
+            Err(VerifyError::MismatchedDepth { offset: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn test_underflow() {
+        assert_eq!(
+            verify(&compile_str("pop")),
+            Err(VerifyError::StackUnderflow { offset: 0 })
+        );
+        assert_eq!(
+            verify(&compile_str("1 +")),
+            Err(VerifyError::StackUnderflow { offset: 5 })
+        );
+        assert_eq!(
+            verify(&compile_str("1 2 pick 2")),
+            Err(VerifyError::StackUnderflow { offset: 10 })
+        );
+    }
+
+    #[test]
+    fn test_invalid_operands() {
+        let mut program = Program::new();
+        program.bytes.push(OpCode::LoadConst as u8);
+        program.push_u32(7);
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError::InvalidConstant {
+                offset: 0,
+                index: 7
+            })
+        );
+
+        let mut program = Program::new();
+        program.add_ident("x");
+        program.push_literal(0);
+        program.push_instruction(Instruction::IncName(0, 3));
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError::InvalidConstant {
+                offset: 5,
+                index: 3
+            })
+        );
+        program.bytes.truncate(5);
+        program.push_instruction(Instruction::IncName(1, 0));
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError::InvalidIdent {
+                offset: 5,
+                index: 1
+            })
+        );
+
+        let mut program = Program::new();
+        program.push_opcode(OpCode::PrepareFuncCall);
+        program.push_literal(1);
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError::InvalidCall { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn test_function() {
+        let mut program = Program::new();
+        let square = program.push_func(|p| {
+            p.push_opcode(OpCode::Dup);
+            p.push_opcode(OpCode::Mul);
+        });
+        program.push_literal(3);
+        program.call_func(square);
+        program.call_func(square);
+        let info = verify(&program).unwrap();
+
+        assert_eq!(
+            info.functions[1],
+            FunctionInfo {
+                entry: square,
+                args: 1,
+                net: Some(0),
+                max_depth: Some(1),
+            }
+        );
+        assert_eq!(info.max_depth(), Some(2));
+        assert_eq!(vm::create_and_run(&program), Ok(vec![Value::Int(81)]));
+
+        let mut program = Program::new();
+        let square = program.push_func(|p| {
+            p.push_opcode(OpCode::Dup);
+            p.push_opcode(OpCode::Mul);
+        });
+        program.call_func(square);
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError::StackUnderflow { offset: 9 })
+        );
+    }
+
+    #[test]
+    fn test_mismatched_return() {
+        let mut program = Program::new();
+        let func = program.push_func(|p| {
+            p.push_if(|p| {
+                p.push_literal(1);
+                p.push_opcode(OpCode::Ret);
+            });
+        });
+        program.push_literal(1);
+        program.call_func(func);
+        assert!(matches!(
+            verify(&program),
+            Err(VerifyError::MismatchedReturn {
+                expected: -1,
+                found: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let mut program = Program::new();
+        let one = program.push_func(|p| _ = p.push_literal(1));
+        let func = program.push_func(|p| {
+            p.push_opcode(OpCode::Dup);
+            p.push_if(|p| _ = p.tail_call_func(one));
+        });
+        program.push_literal(1);
+        program.call_func(func);
+        // The tail call returns with one more value than falling through.
+        assert!(matches!(
+            verify(&program),
+            Err(VerifyError::MismatchedReturn { .. })
+        ));
+    }
+
+    #[test]
+    fn test_return_from_top_level() {
+        for (source, offset) in [("ret", 0), ("1 ret", 5)] {
+            assert_eq!(
+                verify(&compile_str(source)),
+                Err(VerifyError::ReturnFromTopLevel { offset })
+            );
+        }
+
+        let mut program = Program::new();
+        let func = program.push_func(|p| _ = p.push_literal(1));
+        program.tail_call_func(func);
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError::ReturnFromTopLevel { offset: 11 })
+        );
+    }
+
+    #[test]
+    fn test_recursion() {
+        let mut program = Program::new();
+        let entry = program.len() + 5;
+        let countdown = program.push_func(|p| {
+            p.push_opcode(OpCode::Dup);
+            p.push_if(|p| {
+                p.push_literal(1);
+                p.push_opcode(OpCode::Sub);
+                p.call_func(entry);
+            });
+        });
+        program.push_literal(3);
+        program.call_func(countdown);
+        let info = verify(&program).unwrap();
+
+        assert_eq!(
+            info.functions[1],
+            FunctionInfo {
+                entry,
+                args: 1,
+                net: Some(0),
+                max_depth: None,
+            }
+        );
+        assert_eq!(info.max_depth(), None);
+        assert_eq!(vm::create_and_run(&program), Ok(vec![Value::Int(0)]));
+    }
+}
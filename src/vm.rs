@@ -1,24 +1,110 @@
-use crate::{builtins::Builtin, op_codes::OpCode, program::Program, value::Value};
-use std::{
-    collections::HashMap,
-    ops::{Add, Div, Mul, Sub},
+use crate::{
+    binops::{binary_op, unary_op, BinOpError, BinOpResult},
+    builtins::Builtin,
+    dispatch::Decoded,
+    instruction::Instruction,
+    program::Program,
+    traceback::{self, Frame},
+    value::Value,
+    verify::VerifyError,
 };
+use std::{cmp::Ordering, fmt};
 
+/// Panic message for a `Ret` with no call to return to, which verification
+/// rules out.
+pub(crate) const NO_CALLER: &str = "Ret outside of any function call";
+
+/// Runs a program straight from its bytecode, one instruction at a time.
+///
+/// Programs are not verified, so invalid bytecode panics.
 pub struct Vm<'a> {
     bytes: &'a [u8],
     constants: &'a [Value],
     stack: Vec<Value>,
     idents: &'a [String],
-    variables: HashMap<&'a str, Value>,
-    call_stack: Vec<usize>,
+    /// Global variables, indexed by ident.
+    variables: Vec<Option<Value>>,
+    /// The entry and return address of each call that has not returned.
+    call_stack: Vec<(usize, usize)>,
     head: usize,
+    program: &'a Program,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    BinOp(BinOpError),
+    Verify(VerifyError),
+    UndefinedVariable(String),
+    /// A runtime error with the calls that led to it, innermost last.
+    At {
+        error: Box<VmError>,
+        traceback: Vec<Frame>,
+    },
 }
 
-#[allow(clippy::must_use_candidate)]
-pub fn create_and_run(program: &Program) -> Vec<Value> {
-    let mut vm = Vm::from(program);
-    vm.run();
-    vm.stack
+impl VmError {
+    /// The error without the position it was raised at.
+    #[must_use]
+    pub fn kind(&self) -> &Self {
+        match self {
+            Self::At { error, .. } => error.kind(),
+            _ => self,
+        }
+    }
+
+    /// The frames that were active when the error was raised, innermost
+    /// last. Empty for errors raised before the program runs.
+    #[must_use]
+    pub fn traceback(&self) -> &[Frame] {
+        match self {
+            Self::At { traceback, .. } => traceback,
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn at(self, traceback: Vec<Frame>) -> Self {
+        Self::At {
+            error: Box::new(self),
+            traceback,
+        }
+    }
+}
+
+impl From<BinOpError> for VmError {
+    fn from(value: BinOpError) -> Self {
+        Self::BinOp(value)
+    }
+}
+
+impl From<VerifyError> for VmError {
+    fn from(value: VerifyError) -> Self {
+        Self::Verify(value)
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BinOp(err) => write!(f, "{err}"),
+            Self::Verify(err) => write!(f, "{err}"),
+            Self::UndefinedVariable(name) => write!(f, "undefined variable '{name}'"),
+            Self::At { error, traceback } => {
+                write!(f, "{error} at ")?;
+                traceback.last().unwrap().write_position(f)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Verifies and runs `program` with the pre-decoded dispatch loop of
+/// [`Decoded`], returning the remaining stack.
+///
+/// # Errors
+/// Fails if the program does not verify or raises a runtime error.
+pub fn create_and_run(program: &Program) -> Result<Vec<Value>, VmError> {
+    Decoded::new(program)?.run()
 }
 
 impl<'a> From<&'a Program> for Vm<'a> {
@@ -28,137 +114,218 @@ impl<'a> From<&'a Program> for Vm<'a> {
             constants: &value.constants,
             idents: &value.idents,
 
-            variables: HashMap::default(),
+            variables: vec![None; value.idents.len()],
             stack: vec![],
             call_stack: vec![],
             head: 0,
+            program: value,
         }
     }
 }
 
-impl<'a> Vm<'a> {
-    pub fn run(&mut self) {
+impl Vm<'_> {
+    #[must_use]
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+    /// How many calls are waiting to be returned to.
+    #[must_use]
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.len()
+    }
+    /// Offset of the next instruction to run.
+    #[must_use]
+    pub fn head(&self) -> usize {
+        self.head
+    }
+    /// # Errors
+    /// Fails if the program raises a runtime error.
+    pub fn run(&mut self) -> Result<(), VmError> {
         while self.head < self.bytes.len() {
-            self.run_next();
+            self.run_next()?;
+        }
+        Ok(())
+    }
+    fn shuffle(&mut self, inst: Instruction) {
+        let len = self.stack.len();
+        match inst {
+            Instruction::Rot => self.stack[len - 3..].rotate_left(1),
+            Instruction::Over => self.stack.push(self.stack[len - 2].clone()),
+            Instruction::Pick(n) => self.stack.push(self.stack[len - 1 - n as usize].clone()),
+            Instruction::Roll(n) => self.stack[len - 1 - n as usize..].rotate_left(1),
+            Instruction::PopN(n) => self.stack.truncate(len - n as usize),
+            _ => unreachable!("{inst:?}"),
+        }
+    }
+    fn branch(&mut self, inst: Instruction) {
+        let when = matches!(
+            inst,
+            Instruction::PopJumpIfTrue(_) | Instruction::JumpIfTrueOrPop(_)
+        );
+        let top = self.pop_stack();
+        if bool::from(&top) == when {
+            self.head = inst.jump_target().unwrap() as usize;
+            if let Instruction::JumpIfFalseOrPop(_) | Instruction::JumpIfTrueOrPop(_) = inst {
+                self.stack.push(top);
+            }
         }
     }
-    pub fn run_next(&mut self) {
-        let op_code = OpCode::try_from(self.bytes[self.head]).unwrap();
-        self.head += 1;
+    fn call(&mut self, inst: Instruction) {
+        match inst {
+            Instruction::PrepareFuncCall => {
+                let jump = Instruction::decode(self.bytes, self.head).unwrap();
+                let entry = jump.jump_target().unwrap() as usize;
+                self.call_stack.push((entry, self.head + 5));
+            }
+            // A tail call leaves the caller's return address on the call stack.
+            Instruction::TailCall(target) => {
+                if let Some((entry, _)) = self.call_stack.last_mut() {
+                    *entry = target as usize;
+                }
+                self.head = target as usize;
+            }
+            Instruction::Ret => self.head = self.call_stack.pop().expect(NO_CALLER).1,
+            _ => unreachable!("{inst:?}"),
+        }
+    }
+    /// # Errors
+    /// Fails if the instruction raises a runtime error.
+    pub fn run_next(&mut self) -> Result<(), VmError> {
+        let offset = self.head;
+        self.step().map_err(|err| {
+            let traceback = traceback::capture(self.program, self.call_stack.clone(), offset);
+            err.at(traceback)
+        })
+    }
+    fn step(&mut self) -> Result<(), VmError> {
+        let inst = Instruction::decode(self.bytes, self.head).unwrap();
+        self.head += inst.size();
 
-        match op_code {
-            OpCode::Dup => {
+        match inst {
+            Instruction::Dup => {
                 let top = self.stack.last().unwrap().clone();
                 self.stack.push(top);
             }
-            OpCode::Swap => {
+            Instruction::Swap => {
                 let len = self.stack.len();
                 self.stack.swap(len - 1, len - 2);
             }
-            OpCode::DupSwap => {
+            Instruction::DupSwap => {
                 let top = self.stack.last().unwrap().clone();
                 self.stack.push(top);
 
                 let len = self.stack.len();
                 self.stack.swap(len - 2, len - 3);
             }
-            OpCode::Pop => _ = self.stack.pop(),
-            OpCode::Add => self.binop(Value::add),
-            OpCode::Sub => self.binop(Value::sub),
-            OpCode::Mul => self.binop(Value::mul),
-            OpCode::Div => self.binop(Value::div),
-
-            OpCode::Le => self.binop(cmp(Value::le)),
-            OpCode::Lt => self.binop(cmp(Value::lt)),
-            OpCode::Ge => self.binop(cmp(Value::ge)),
-            OpCode::Gt => self.binop(cmp(Value::gt)),
-            OpCode::Eq => self.binop(cmp(Value::eq)),
-            OpCode::Ne => self.binop(cmp(Value::ne)),
-
-            OpCode::UnaryNot => {
-                let val = !bool::from(&self.pop_stack());
-                self.stack.push(val.into());
+            Instruction::Pop => _ = self.stack.pop(),
+            Instruction::Rot
+            | Instruction::Over
+            | Instruction::Pick(_)
+            | Instruction::Roll(_)
+            | Instruction::PopN(_) => self.shuffle(inst),
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Mod
+            | Instruction::Pow
+            | Instruction::IntDiv
+            | Instruction::BitAnd
+            | Instruction::BitOr
+            | Instruction::BitXor
+            | Instruction::Shl
+            | Instruction::Shr
+            | Instruction::Le
+            | Instruction::Lt
+            | Instruction::Ge
+            | Instruction::Gt
+            | Instruction::Eq
+            | Instruction::Ne => self.binop(binary_op(inst.op_code()).unwrap())?,
+
+            Instruction::UnaryNot | Instruction::BitNot => {
+                let val = unary_op(inst.op_code()).unwrap()(self.pop_stack())?;
+                self.stack.push(val);
             }
 
-            OpCode::LoadConst => {
-                let index = self.read_u32() as usize;
-                let value = self.constants[index].clone();
+            Instruction::LoadConst(index) => {
+                let value = self.constants[index as usize].clone();
                 self.stack.push(value);
             }
-            OpCode::Jump => return self.head = self.read_u32() as usize,
-            OpCode::PopJumpIfFalse => {
-                let should_jump = !bool::try_from(&self.pop_stack()).unwrap();
-                if should_jump {
-                    return self.head = self.read_u32() as usize;
-                }
+            Instruction::Jump(target) => self.head = target as usize,
+            Instruction::PopJumpIfFalse(_)
+            | Instruction::PopJumpIfTrue(_)
+            | Instruction::JumpIfFalseOrPop(_)
+            | Instruction::JumpIfTrueOrPop(_) => self.branch(inst),
+            Instruction::PrepareFuncCall | Instruction::TailCall(_) | Instruction::Ret => {
+                self.call(inst);
             }
-            OpCode::Ret => self.head = self.call_stack.pop().unwrap(),
-            OpCode::PrepareFuncCall => self.call_stack.push(self.head + 5),
-            OpCode::StoreName => {
+            Instruction::StoreName(index) => {
                 let top = self.pop_stack();
-
-                let index = self.read_u32() as usize;
-                let ident: &'a str = &self.idents[index];
-                self.variables.insert(ident, top);
+                self.variables[index as usize] = Some(top);
             }
-            OpCode::LoadName => {
-                let index = self.read_u32() as usize;
-                let ident: &str = &self.idents[index];
-                let val = self.variables.get(ident).unwrap().clone();
-                self.stack.push(val);
+            Instruction::LoadName(index) => {
+                let Some(val) = &self.variables[index as usize] else {
+                    let name = self.idents[index as usize].clone();
+                    return Err(VmError::UndefinedVariable(name));
+                };
+                self.stack.push(val.clone());
             }
-            OpCode::LoadBuiltin => {
-                let builtin = Builtin::try_from(self.bytes[self.head]).unwrap();
-                self.run_builtin(builtin);
+            Instruction::LoadBuiltin(builtin) => run_builtin(builtin, &mut self.stack),
+            Instruction::AddConst(index) => {
+                let top = self.pop_stack();
+                let constant = self.constants[index as usize].clone();
+                self.stack.push((top + constant)?);
             }
-            OpCode::StopCode => unreachable!("StopCode"),
-            OpCode::Nop => unreachable!("Nop"),
-        }
-
-        self.head += op_code.size_operand();
-    }
-    #[allow(clippy::cast_possible_truncation)]
-    fn run_builtin(&mut self, builtin: Builtin) {
-        match builtin {
-            Builtin::Print => {
-                let val = self.pop_stack();
-                println!("{val}");
+            Instruction::JumpIfNotLtConst(index, target) => {
+                let top = self.stack.last().unwrap();
+                let constant = &self.constants[index as usize];
+                if !top.compare(constant)?.is_some_and(Ordering::is_lt) {
+                    self.head = target as usize;
+                }
             }
-            Builtin::Exit => {
-                let val = self.stack.pop().unwrap_or(Value::Int(0));
-                let code = match val {
-                    Value::Int(val) => val as i32,
-                    Value::Float(float) => float as i32,
-                    Value::Str(_) => 0,
+            Instruction::IncName(name, index) => {
+                let Some(val) = self.variables[name as usize].as_mut() else {
+                    let name = self.idents[name as usize].clone();
+                    return Err(VmError::UndefinedVariable(name));
                 };
-                std::process::exit(code);
+                val.add_in_place(self.constants[index as usize].clone())?;
             }
+            Instruction::Nop => (),
         }
+        Ok(())
     }
     fn pop_stack(&mut self) -> Value {
         self.stack.pop().expect("Failed to pop from stack.")
     }
-    fn binop<F>(&mut self, func: F)
+    fn binop<F>(&mut self, func: F) -> Result<(), VmError>
     where
-        F: FnOnce(Value, Value) -> Value,
+        F: FnOnce(Value, Value) -> BinOpResult,
     {
         let rhs = self.pop_stack();
         let lhs = self.pop_stack();
-        self.stack.push(func(lhs, rhs));
+        self.stack.push(func(lhs, rhs)?);
+        Ok(())
     }
-    fn read_u32(&self) -> u32 {
-        u32::from_le_bytes(self.read_arr())
-    }
-    fn read_arr<const LEN: usize>(&self) -> [u8; LEN] {
-        let slice = &self.bytes[self.head..self.head + LEN];
-        slice.try_into().unwrap()
+}
+
+pub(crate) fn run_builtin(builtin: Builtin, stack: &mut Vec<Value>) {
+    match builtin {
+        Builtin::Print => {
+            let val = stack.pop().expect("Failed to pop from stack.");
+            println!("{val}");
+        }
+        Builtin::Exit => exit(stack.pop()),
     }
 }
 
-///
-fn cmp<F>(func: F) -> impl FnOnce(Value, Value) -> Value
-where
-    F: FnOnce(&Value, &Value) -> bool,
-{
-    |lhs, rhs| Value::from(func(&lhs, &rhs))
+/// Exits the process with `val` as the exit code, or 0 if there is none.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn exit(val: Option<Value>) -> ! {
+    let code = match val.unwrap_or(Value::Int(0)) {
+        Value::Int(val) => val as i32,
+        Value::BigInt(int) => int.to_f64() as i32,
+        Value::Float(float) => float as i32,
+        Value::Str(_) => 0,
+    };
+    std::process::exit(code);
 }